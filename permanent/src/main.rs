@@ -0,0 +1,134 @@
+use clap::{Parser, Subcommand};
+use permanent_common::action::{Action, ActionChain};
+
+mod stage;
+use stage::{Stage, StageAction, TesterOptions};
+mod suite;
+use suite::Outcome;
+mod minimize;
+
+/// Run the stages `from` to `to` in a work dir. A seed overrides the one of the configs for the
+/// cig stage. Returns false if the run was aborted.
+fn run_stages(work_dir: &String, from: Stage, to: Stage, tester: &TesterOptions, seed: Option<u64>) -> bool {
+    let vm_config = permanent_trace::read_vm_config(work_dir);
+    let mut test_config = permanent_trace::read_test_config(work_dir);
+    if let Some(seed) = seed {
+        test_config.model.seed = Some(seed);
+    }
+
+    let mut chain = ActionChain::with_profiling(format!("{}/profile.out", work_dir).as_str());
+    for stage in Stage::all().iter().filter(|stage| (from..=to).contains(*stage)) {
+        chain.append(Box::new(StageAction {
+            stage: *stage,
+            work_dir: work_dir.clone(),
+            vm_config: vm_config.clone(),
+            test_config: test_config.clone(),
+            tester: tester.clone(),
+        }));
+    }
+    chain.run().is_some()
+}
+
+fn main() {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Run { work_dir, from, to, snapshot, jobs, seed } => {
+            if from > to {
+                panic!("stage {} comes after stage {}", from.name(), to.name());
+            }
+            if !run_stages(&work_dir, from, to, &TesterOptions { snapshot, jobs }, seed) {
+                std::process::exit(1);
+            }
+        },
+        Command::Suite { out_dir, vms, tests, snapshot, jobs, seed } => {
+            let tester = TesterOptions { snapshot, jobs };
+            let vm_names: Vec<String> = vms.iter().map(suite::config_name).collect();
+            let test_names: Vec<String> = tests.iter().map(suite::config_name).collect();
+
+            let mut outcomes: Vec<Vec<Outcome>> = Vec::new();
+            for (vm, vm_name) in vms.iter().zip(&vm_names) {
+                let mut vm_outcomes = Vec::new();
+                for (test, test_name) in tests.iter().zip(&test_names) {
+                    println!("=== {} on {}", test_name, vm_name);
+                    let work_dir = format!("{}/{}/{}", out_dir, vm_name, test_name);
+                    suite::create_work_dir(&work_dir, vm, test);
+                    vm_outcomes.push(suite::run_test(&work_dir, || {
+                        run_stages(&work_dir, Stage::Analyse, Stage::Report, &tester, seed);
+                    }));
+                }
+                outcomes.push(vm_outcomes);
+            }
+
+            println!();
+            suite::print_matrix(&vm_names, &test_names, &outcomes);
+            suite::write_suite_junit(&format!("{}/report.xml", out_dir), &vm_names, &test_names, &outcomes);
+            if !outcomes.iter().flatten().all(|outcome| matches!(outcome, Outcome::Passed(_))) {
+                std::process::exit(1);
+            }
+        },
+        Command::Minimize { work_dir, hash, snapshot } => {
+            minimize::minimize(&work_dir, hash.as_str(), snapshot);
+        },
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// run all stages of a test in a work dir
+    Run {
+        work_dir: String,
+        /// first stage to run
+        #[clap(long, value_enum, default_value_t = Stage::Analyse)]
+        from: Stage,
+        /// last stage to run
+        #[clap(long, value_enum, default_value_t = Stage::Report)]
+        to: Stage,
+        /// boot the VM only once and resume every post failure run from a snapshot
+        #[clap(short, long, action)]
+        snapshot: bool,
+        /// number of post failure VMs to run in parallel
+        #[clap(short, long, default_value_t = 1)]
+        jobs: usize,
+        /// seed of the random choice of crash images, overrides the configs. The seed used is
+        /// recorded in model.yaml.
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// run every test on every VM, each in a work dir {out_dir}/{vm}/{test}
+    Suite {
+        out_dir: String,
+        /// VM config, can be given multiple times
+        #[clap(long = "vm", required = true, num_args = 1..)]
+        vms: Vec<String>,
+        /// test config, can be given multiple times
+        #[clap(long = "test", required = true, num_args = 1..)]
+        tests: Vec<String>,
+        /// boot the VM only once per test and resume every post failure run from a snapshot
+        #[clap(short, long, action)]
+        snapshot: bool,
+        /// number of post failure VMs to run in parallel
+        #[clap(short, long, default_value_t = 1)]
+        jobs: usize,
+        /// seed of the random choice of crash images, overrides the configs. The seed used is
+        /// recorded in model.yaml.
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// shrink the stores applied in a tested crash image to a minimal subset that still leads to
+    /// the same state, by re-running post failure VMs
+    Minimize {
+        work_dir: String,
+        /// hash of the crash image
+        hash: String,
+        /// boot the VM only once and resume every post failure run from a snapshot
+        #[clap(short, long, action)]
+        snapshot: bool,
+    },
+}
@@ -0,0 +1,175 @@
+//! Shrink the unpersisted stores applied in a crash image to a minimal subset that still leads to
+//! the same state after recovery.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use serde::Serialize;
+use permanent_common::hash::{CrashHash, StateHash};
+use permanent_cig::{AppliedStore, CrashImageStores, describe_trace_entries};
+use permanent_tester::Job;
+
+/// written to {work_dir}/minimized/{crash_hash}.json
+#[derive(Serialize)]
+struct Minimized {
+    crash_hash: CrashHash,
+    device: &'static str,
+    /// name of the crash image combination in states.index
+    crash_name: String,
+    state_hash: StateHash,
+    trace_entry_id: usize,
+    /// one per applied part of a store
+    applied: Vec<AppliedStore>,
+    minimal: Vec<AppliedStore>,
+    /// the nothing-persisted content with only the minimal stores applied, saved next to the report
+    minimal_hash: CrashHash,
+    post_failure_runs: usize,
+}
+
+/// Delta debugging (ddmin): find a subset of `items` for which `test` holds and which is
+/// 1-minimal, i.e. removing any single item makes `test` fail. `test` must hold for `items`.
+pub fn ddmin<T: Clone, F: FnMut(&[T]) -> bool>(items: &[T], mut test: F) -> Vec<T> {
+    let mut current = items.to_vec();
+    let mut granularity = 2;
+    while current.len() >= 2 {
+        let chunks: Vec<Vec<T>> = current.chunks(current.len().div_ceil(granularity)).map(|chunk| chunk.to_vec()).collect();
+        if let Some(chunk) = chunks.iter().find(|chunk| test(chunk)) {
+            current = chunk.clone();
+            granularity = 2;
+            continue;
+        }
+        let complement = |i: usize| chunks.iter().enumerate()
+            .filter(|(j, _)| *j != i)
+            .flat_map(|(_, chunk)| chunk.iter().cloned())
+            .collect::<Vec<T>>();
+        // with two chunks, the complements are the chunks themselves
+        if let Some(complement) = (0..chunks.len()).filter(|_| chunks.len() > 2).map(complement).find(|complement| test(complement)) {
+            current = complement;
+            granularity = (granularity - 1).max(2);
+            continue;
+        }
+        if granularity >= current.len() {
+            break;
+        }
+        granularity = (granularity * 2).min(current.len());
+    }
+    current
+}
+
+/// Find the state of a crash image and the name of the combination it was tested in.
+fn find_state(work_dir: &String, hash: &CrashHash) -> (StateHash, String) {
+    let path = format!("{}/states.index", work_dir);
+    let file = File::open(path.as_str()).unwrap_or_else(|_| panic!("could not open {}. run the tester first.", path));
+    let states: BTreeMap<StateHash, Vec<String>> = serde_json::from_reader(BufReader::new(file)).expect("could not parse states.index");
+    let hash = hash.to_string();
+    states.into_iter()
+        .flat_map(|(state, names)| names.into_iter().map(move |name| (state.clone(), name)))
+        .find(|(_, name)| name.split('_').any(|part| part == hash))
+        .unwrap_or_else(|| panic!("crash image {} has not been tested", hash))
+}
+
+pub fn minimize(work_dir: &String, hash: &str, snapshot: bool) {
+    let vm_config = permanent_trace::read_vm_config(work_dir);
+    let hash: CrashHash = hash.parse().expect("invalid crash image hash");
+    let stores = CrashImageStores::load(work_dir, &vm_config, &hash).expect("could not reconstruct the stores of the crash image");
+
+    let (state_hash, crash_name) = find_state(work_dir, &hash);
+    // the crash image of the other device stays as tested
+    let (p, n) = vm_config.have_pmem_nvme();
+    let mut parts = crash_name.split('_').map(|part| part.parse::<CrashHash>().expect("invalid crash image name in states.index"));
+    let pmem_hash = if p { parts.next() } else { None };
+    let nvme_hash = if n { parts.next() } else { None };
+    println!("minimizing {} stores of {} crash image {}, state {}",
+        stores.applied().len(), stores.device, hash, state_hash);
+
+    // the images of the subsets are not crash images of the work dir, so they are booted from a
+    // scratch dir that the tester does not look at. the trace dirs of the runs, which keep the VM
+    // logs, go to runs/ and leave those of the tester alone.
+    let out_dir = format!("{}/minimized", work_dir);
+    let scratch_dir = format!("{}/scratch", out_dir);
+    let runs_dir = format!("{}/runs", out_dir);
+    std::fs::create_dir_all(scratch_dir.as_str()).expect("could not create scratch dir");
+    std::fs::create_dir_all(runs_dir.as_str()).expect("could not create runs dir");
+
+    let snapshot = snapshot && permanent_tester::take_snapshot(work_dir);
+    let mut results: HashMap<CrashHash, bool> = HashMap::new();
+    let mut runs = 0;
+    let mut reproduces = |applied: &[AppliedStore]| -> bool {
+        let image = stores.build(applied);
+        let image_hash = CrashHash::of(image.as_slice());
+        if let Some(result) = results.get(&image_hash) {
+            return *result;
+        }
+        let path = format!("{}/{}.raw", scratch_dir, image_hash);
+        std::fs::write(path.as_str(), image.as_slice()).expect("could not write crash image");
+        let trace_parent = Some(runs_dir.clone());
+        let job = match stores.device {
+            "pmem" => Job { pmem_hash: Some(image_hash.clone()), pmem_image: Some(path.clone()), nvme_hash: nvme_hash.clone(), trace_parent, ..Default::default() },
+            _ => Job { pmem_hash: pmem_hash.clone(), nvme_hash: Some(image_hash.clone()), nvme_image: Some(path.clone()), trace_parent, ..Default::default() },
+        };
+        let dump = permanent_tester::run_job(work_dir, &job, snapshot, true);
+        std::fs::remove_file(path.as_str()).expect("could not remove crash image");
+        // a run that could not be completed does not reproduce anything
+        let result = dump.is_some_and(|dump| StateHash::of(dump.as_slice()) == state_hash);
+        runs += 1;
+        println!("[run {}] {} stores: {}", runs, applied.len(), if result { "reproduced" } else { "not reproduced" });
+        results.insert(image_hash, result);
+        result
+    };
+
+    // ddmin requires the state for all stores
+    if !reproduces(stores.applied()) {
+        panic!("crash image {} does not lead to state {} again. is the recovery deterministic?", hash, state_hash);
+    }
+    let minimal = if reproduces(&[]) {
+        println!("the nothing-persisted image already leads to the state");
+        Vec::new()
+    } else {
+        ddmin(stores.applied(), &mut reproduces)
+    };
+    std::fs::remove_dir(scratch_dir.as_str()).expect("could not remove scratch dir");
+
+    let ids: BTreeSet<u64> = minimal.iter().map(|store| store.id as u64).collect();
+    let descriptions = describe_trace_entries(work_dir, &vm_config, &ids).expect("could not read the trace");
+    println!("{} of {} stores after id {} are needed:", minimal.len(), stores.applied().len(), stores.metadata.trace_entry_id);
+    for store in minimal.iter() {
+        println!("  id {} at {:#x}..{:#x}: {}", store.id, store.address, store.address + store.len, descriptions[&(store.id as u64)]);
+    }
+
+    let image = stores.build(minimal.as_slice());
+    let minimal_hash = CrashHash::of(image.as_slice());
+    std::fs::write(format!("{}/{}.raw", out_dir, minimal_hash).as_str(), image.as_slice()).expect("could not write minimal crash image");
+    let report = Minimized {
+        crash_hash: hash.clone(),
+        device: stores.device,
+        crash_name,
+        state_hash,
+        trace_entry_id: stores.metadata.trace_entry_id,
+        applied: stores.applied().to_vec(),
+        minimal,
+        minimal_hash,
+        post_failure_runs: runs,
+    };
+    let path = format!("{}/{}.json", out_dir, hash);
+    let file = File::create(path.as_str()).expect("could not create report");
+    serde_json::to_writer_pretty(BufWriter::new(file), &report).expect("could not write report");
+    println!("report written to {}", path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ddmin() {
+        let items: Vec<usize> = (0..10).collect();
+        let mut tests = 0;
+        let minimal = ddmin(&items, |subset| {
+            tests += 1;
+            subset.contains(&3) && subset.contains(&7)
+        });
+        assert_eq!(minimal, vec![3, 7]);
+        assert!(tests < 1 << items.len());
+        assert_eq!(ddmin(&items, |subset| subset.contains(&9)), vec![9]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::time::SystemTime;
+use clap::ValueEnum;
+use permanent_common::action::Action;
+use permanent_common::config::{VmConfig, TestConfig, TraceConfig, TraceType};
+use permanent_common::profiler::{Profile, Measurement};
+use permanent_cig::{CrashImageGenerator, check_traces, remove_outputs};
+
+/// pipeline stages in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Stage {
+    /// trace the test case
+    Analyse,
+    /// trace the reads of the recovery
+    PostSuccess,
+    /// generate crash images
+    Cig,
+    /// run the recovery on every crash image
+    Tester,
+    /// check the expected properties
+    Report,
+}
+
+impl Stage {
+    pub fn all() -> &'static [Stage] {
+        Stage::value_variants()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Analyse => "analyse",
+            Stage::PostSuccess => "post-success",
+            Stage::Cig => "cig",
+            Stage::Tester => "tester",
+            Stage::Report => "report",
+        }
+    }
+}
+
+/// options of the tester stage
+#[derive(Clone)]
+pub struct TesterOptions {
+    pub snapshot: bool,
+    pub jobs: usize,
+}
+
+/// a single stage of a test run in a work dir
+pub struct StageAction {
+    pub stage: Stage,
+    pub work_dir: String,
+    pub vm_config: VmConfig,
+    pub test_config: TestConfig,
+    pub tester: TesterOptions,
+}
+
+impl StageAction {
+    /// Run the stage. Returns false if the run must not continue.
+    fn execute(&self) -> bool {
+        let work_dir = &self.work_dir;
+        match self.stage {
+            Stage::Analyse => {
+                let trace_config = TraceConfig::new(work_dir, TraceType::Analyse);
+                permanent_trace::trace(work_dir, &self.vm_config, Some(&self.test_config), &trace_config, true, false);
+            },
+            Stage::PostSuccess => {
+                let trace_config = TraceConfig::new(work_dir, TraceType::PostSuccess { pmem_hash: None, nvme_hash: None });
+                permanent_trace::trace(work_dir, &self.vm_config, None, &trace_config, true, false);
+            },
+            Stage::Cig => {
+                // the previous crash images stay if they cannot be generated again
+                check_traces(work_dir).expect("could not generate crash images");
+                remove_outputs(work_dir).expect("could not remove previous crash images");
+                let mut cig = CrashImageGenerator::new(work_dir, &self.vm_config, &self.test_config);
+                // the read set only narrows the crash images, the post-success stage might have been skipped
+                let read_trace = TraceConfig::new(work_dir, TraceType::PostSuccess { pmem_hash: None, nvme_hash: None }).trace_path();
+                if Path::new(read_trace.as_str()).exists() {
+                    cig.load_read_set().expect("could not load read set");
+                } else {
+                    eprintln!("WARNING: no post-success trace at {}. generating crash images without the read set.", read_trace);
+                }
+                cig.replay_trace();
+            },
+            Stage::Tester => {
+                permanent_tester::run_tests(work_dir, &self.vm_config, self.tester.snapshot, self.tester.jobs);
+            },
+            Stage::Report => {
+                let report = permanent_report::report(work_dir, None).expect("could not create report");
+                return report.passed();
+            },
+        }
+        true
+    }
+}
+
+impl Action for StageAction {
+    fn run(&self) -> Option<Vec<Profile>> {
+        println!("---{}", self.stage.name());
+        let start = SystemTime::now();
+        let proceed = self.execute();
+        let end = SystemTime::now();
+        let profile = Profile::from_measurement(self.stage.name(), Measurement::new(start, end));
+        println!("time: {:?}", profile.average_duration());
+        proceed.then(|| vec![profile])
+    }
+}
@@ -0,0 +1,127 @@
+//! Run every test on every VM, each in its own work dir.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use permanent_common::config::{VmConfig, TestConfig};
+use permanent_report::Report;
+use permanent_report::summary::write_junit;
+
+/// outcome of a single test on a single VM
+pub enum Outcome {
+    Passed(Report),
+    Failed(Report),
+    /// a stage did not finish or the results could not be analysed
+    Error(String),
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Passed(_) => "pass",
+            Outcome::Failed(_) => "FAIL",
+            Outcome::Error(_) => "ERROR",
+        }
+    }
+}
+
+/// name of a config file without directory and extension
+pub fn config_name(path: &String) -> String {
+    Path::new(path.as_str()).file_stem().expect("invalid config path").to_string_lossy().into_owned()
+}
+
+fn read_yaml<T: serde::de::DeserializeOwned>(path: &String) -> T {
+    let file = File::open(path.as_str()).unwrap_or_else(|_| panic!("could not open {}", path));
+    serde_yaml::from_reader(BufReader::new(file)).unwrap_or_else(|e| panic!("could not parse {}: {}", path, e))
+}
+
+/// Create a work dir with the given configs and zeroed base images. An existing work dir is
+/// reused, but all stages run again from the analyse stage.
+pub fn create_work_dir(work_dir: &String, vm_path: &String, test_path: &String) {
+    let vm_config: VmConfig = read_yaml(vm_path);
+    // fail early on invalid test configs
+    let _: TestConfig = read_yaml(test_path);
+
+    std::fs::create_dir_all(work_dir.as_str()).expect("could not create work dir");
+    std::fs::copy(vm_path.as_str(), format!("{}/vm_config.yaml", work_dir).as_str()).expect("could not copy vm config");
+    std::fs::copy(test_path.as_str(), format!("{}/test_config.yaml", work_dir).as_str()).expect("could not copy test config");
+
+    let (p, n) = vm_config.have_pmem_nvme();
+    if p {
+        let len = vm_config.pmem_len.expect("pmem_len missing in vm config");
+        create_zeroed_image(&format!("{}/pmem_base.raw", work_dir), len);
+    }
+    if n {
+        let len = vm_config.nvme_len.expect("nvme_len missing in vm config");
+        create_zeroed_image(&format!("{}/nvme_base.raw", work_dir), len);
+    }
+}
+
+fn create_zeroed_image(path: &String, len: u64) {
+    // the file is sparse, so this is cheap even for large devices. the VM config might have
+    // changed the size since the work dir was created.
+    File::create(path.as_str())
+        .and_then(|file| file.set_len(len))
+        .expect("could not create base image");
+}
+
+/// Run all stages in a work dir and analyse the results. Panics of the stages are caught, so
+/// that the remaining tests of the suite can still run.
+pub fn run_test(work_dir: &String, run: impl FnOnce()) -> Outcome {
+    // VMs of the panicking stage are killed when they are dropped during the unwind
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(run)) {
+        eprintln!("WARNING: test in {} did not finish", work_dir);
+        let msg = payload.downcast_ref::<&str>().map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        return Outcome::Error(format!("a stage did not finish: {}", msg));
+    }
+    match permanent_report::analyse(work_dir) {
+        Ok(report) if report.passed() => Outcome::Passed(report),
+        Ok(report) => Outcome::Failed(report),
+        Err(e) => {
+            eprintln!("WARNING: could not analyse {}: {:?}", work_dir, e);
+            Outcome::Error(format!("could not analyse the results: {:#}", e))
+        },
+    }
+}
+
+/// Print the pass/fail matrix with one row per test and one column per VM.
+pub fn print_matrix(vms: &[String], tests: &[String], outcomes: &[Vec<Outcome>]) {
+    let first_width = tests.iter().map(|test| test.len()).max().unwrap_or(0).max("test".len());
+    let widths: Vec<usize> = vms.iter().map(|vm| vm.len().max("ERROR".len())).collect();
+
+    print!("{:first_width$}", "test");
+    for (vm, width) in vms.iter().zip(&widths) {
+        print!("  {:width$}", vm);
+    }
+    println!();
+    for (t, test) in tests.iter().enumerate() {
+        print!("{:first_width$}", test);
+        for (v, width) in widths.iter().enumerate() {
+            print!("  {:width$}", outcomes[v][t].label());
+        }
+        println!();
+    }
+}
+
+/// Write JUnit XML with one test suite per test and VM. Tests that did not finish have a single
+/// test case with an error.
+pub fn write_suite_junit(path: &String, vms: &[String], tests: &[String], outcomes: &[Vec<Outcome>]) {
+    let mut names = Vec::new();
+    let mut results = Vec::new();
+    for (v, vm) in vms.iter().enumerate() {
+        for (t, test) in tests.iter().enumerate() {
+            names.push(format!("{}/{}", vm, test));
+            results.push(match &outcomes[v][t] {
+                Outcome::Passed(report) | Outcome::Failed(report) => Ok(report),
+                Outcome::Error(msg) => Err(msg.as_str()),
+            });
+        }
+    }
+    let suites: Vec<(&str, Result<&Report, &str>)> = names.iter().map(|name| name.as_str()).zip(results).collect();
+    let mut writer = BufWriter::new(File::create(path.as_str()).expect("could not create JUnit XML"));
+    write_junit(&mut writer, &suites).expect("could not write JUnit XML");
+    writer.flush().expect("could not write JUnit XML");
+}
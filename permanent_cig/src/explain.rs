@@ -0,0 +1,119 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{Context, Result};
+use permanent_common::config::{VmConfig, TraceConfig, TraceType};
+use permanent_common::symbolize::Symbolizer;
+use permanent_common::trace::{TraceEntry, PmemEvent, NvmeEvent, IndexedTrace, parse_trace_file_bin};
+
+use crate::{crash_metadata, AppliedStore};
+use crate::image::CrashHash;
+
+/// Read the analyse trace entries with the given ids.
+fn load_entries(work_dir: &String, ids: &BTreeSet<u64>) -> Result<HashMap<u64, TraceEntry>> {
+    let (Some(first), Some(last)) = (ids.first().copied(), ids.last().copied()) else {
+        return Ok(HashMap::new());
+    };
+    let trace_path = TraceConfig::new(work_dir, TraceType::Analyse).trace_path();
+    // with an index, skip everything before the first store
+    let entries: Box<dyn Iterator<Item = Result<TraceEntry>>> = match IndexedTrace::open(trace_path.as_str()) {
+        Ok(trace) => Box::new(trace.seek_to_id(first)?),
+        Err(_) => {
+            let file = File::open(trace_path.as_str()).with_context(|| format!("could not open {}", trace_path))?;
+            Box::new(parse_trace_file_bin(BufReader::new(file))?)
+        },
+    };
+    let mut found = HashMap::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.id() > last {
+            break;
+        }
+        if ids.contains(&entry.id()) {
+            found.insert(entry.id(), entry);
+        }
+    }
+    Ok(found)
+}
+
+/// Describe the analyse trace entries with the given ids, e.g. "pmem store of 8 bytes at 0x40 by
+/// vCPU 0 at zil_pmem_prb_write+0x4a". Instruction addresses are symbolized if the kernel build of
+/// the vm config has symbols.
+pub fn describe_trace_entries(work_dir: &String, vm_config: &VmConfig, ids: &BTreeSet<u64>) -> Result<HashMap<u64, String>> {
+    let entries = load_entries(work_dir, ids)?;
+    let symbolizer = Symbolizer::for_kernel(vm_config.kernel_path.as_str()).ok();
+    Ok(ids.iter()
+        .map(|id| (*id, entries.get(id).map_or("not in the trace".to_string(), |entry| describe(entry, symbolizer.as_ref()))))
+        .collect())
+}
+
+/// number of bytes written by a store or write entry
+fn written_len(entry: &TraceEntry) -> Option<usize> {
+    match entry {
+        TraceEntry::Pmem { event: PmemEvent::Write { size, .. }, .. } => Some(*size as usize),
+        TraceEntry::Nvme { id: _, event: NvmeEvent::Write { length, .. } } => Some(*length as usize),
+        _ => None,
+    }
+}
+
+fn describe(entry: &TraceEntry, symbolizer: Option<&Symbolizer>) -> String {
+    match entry {
+        TraceEntry::Pmem { id: _, event, rip, vcpu } => {
+            let mut s = match event {
+                PmemEvent::Write { address, size, content: _, non_temporal } => {
+                    format!("pmem {}store of {} bytes at {:#x}", if *non_temporal { "non-temporal " } else { "" }, size, address)
+                },
+                other => format!("pmem {:?}", other),
+            };
+            s.push_str(format!(" by vCPU {}", vcpu).as_str());
+            match symbolizer.and_then(|symbolizer| symbolizer.symbolize(*rip)) {
+                Some(location) => s.push_str(format!(" at {}", location).as_str()),
+                None => s.push_str(format!(" at {:#x}", rip).as_str()),
+            }
+            s
+        },
+        TraceEntry::Nvme { id: _, event: NvmeEvent::Write { offset, length, data: _ } } => {
+            format!("nvme write of {} bytes at offset {:#x}", length, offset)
+        },
+        other => format!("{:?}", other),
+    }
+}
+
+/// Print how the crash image with the given hash was generated: where in the trace, and which
+/// unpersisted stores it contains. Of torn stores, the applied parts are listed.
+pub fn explain(work_dir: &String, vm_config: &VmConfig, hash: &str) -> Result<()> {
+    let hash: CrashHash = hash.parse().context("invalid crash image hash")?;
+    let found = crash_metadata(work_dir, &hash)?;
+
+    let ids = found.iter()
+        .flat_map(|(_, records)| records.iter())
+        .flat_map(|record| record.applied.iter().map(|store| store.id as u64))
+        .collect();
+    let entries = load_entries(work_dir, &ids)?;
+    let symbolizer = Symbolizer::for_kernel(vm_config.kernel_path.as_str()).ok();
+
+    for (device, records) in found {
+        println!("{} crash image {}", device, hash);
+        for record in records {
+            let after = record.prev_checkpoint_value.map_or("before the first checkpoint".to_string(), |value| format!("after checkpoint {}", value));
+            let applied_ids = record.applied_ids();
+            println!("  generated at id {} {}: {:?}, {} stores applied",
+                record.trace_entry_id, after, record.persistence_type, applied_ids.len());
+            for id in applied_ids {
+                let Some(entry) = entries.get(&(id as u64)) else {
+                    println!("    id {}: not in the trace", id);
+                    continue;
+                };
+                println!("    id {}: {}", id, describe(entry, symbolizer.as_ref()));
+                let parts: Vec<&AppliedStore> = record.applied.iter().filter(|store| store.id == id).collect();
+                let applied_len: usize = parts.iter().map(|store| store.len).sum();
+                if written_len(entry).is_some_and(|len| applied_len < len) {
+                    let ranges = parts.iter().map(|store| format!("{:#x}..{:#x}", store.address, store.address + store.len)).collect::<Vec<_>>();
+                    println!("      torn, only {} bytes applied: {}", applied_len, ranges.join(", "));
+                }
+            }
+        }
+    }
+    Ok(())
+}
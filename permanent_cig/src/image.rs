@@ -1,26 +1,22 @@
-use std::fs::File;
-use std::io::Write;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{Context, Result};
 
-#[derive(PartialEq, Eq, Hash, Clone)]
-pub struct CrashHash(blake3::Hash);                                                                                   
-                                                                                                                      
-impl serde::Serialize for CrashHash {                                                                                 
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>                                                  
-    where                                                                                                             
-        S: serde::Serializer                                                                                          
-    {                                                                                                                 
-        serializer.serialize_str(&self.0.to_hex())                                                                    
-    }                                                                                                                 
-}
+pub use permanent_common::hash::CrashHash;
+use permanent_common::image::ImageDelta;
 
+/// Stores every unique crash image once, as a delta against the base image of its device.
 pub struct ImagePool {
+    work_dir: String,
     crash_dir: String,
+    /// sum of the delta sizes
     size: usize,
     size_max: usize,
     hashes: HashSet<CrashHash>,
+    /// base images by file name, loaded on first use
+    bases: HashMap<String, Vec<u8>>,
+    /// images not stored because the size limit was reached
+    dropped: usize,
 }
 
 impl ImagePool {
@@ -28,10 +24,13 @@ impl ImagePool {
         let crash_dir = format!("{}/crash_images", work_dir);
         std::fs::create_dir(crash_dir.as_str()).context("could not create crash images directory")?;
         Ok(Self {
+            work_dir: work_dir.clone(),
             crash_dir,
             size: 0,
             size_max: usize::MAX,
             hashes: HashSet::new(),
+            bases: HashMap::new(),
+            dropped: 0,
         })
     }
 
@@ -41,20 +40,34 @@ impl ImagePool {
         Ok(pool)
     }
 
-    pub fn persist(&mut self, data: &[u8]) -> Result<(bool, CrashHash)> {
-        let hash = CrashHash(blake3::hash(data));
-        if self.hashes.insert(hash.clone()) {
-            // first time encountering this hash
-            self.size += data.len();
-            if self.size > self.size_max {
-                panic!("image pool size limit exceeded");
+    /// Store a crash image of the device with the given base image (e.g. `pmem_base.raw`).
+    /// Returns None if the image is new but the size limit is reached; it is not stored then.
+    pub fn persist(&mut self, base_name: &str, data: &[u8]) -> Result<Option<CrashHash>> {
+        let hash = CrashHash::of(data);
+        if self.hashes.contains(&hash) {
+            return Ok(Some(hash));
+        }
+        // first time encountering this hash
+        if !self.bases.contains_key(base_name) {
+            let path = format!("{}/{}", self.work_dir, base_name);
+            let base = std::fs::read(path.as_str()).with_context(|| format!("could not read {}", path))?;
+            self.bases.insert(base_name.to_string(), base);
+        }
+        let delta = ImageDelta::diff(base_name, self.bases[base_name].as_slice(), data);
+        if self.size + delta.size() > self.size_max {
+            if self.dropped == 0 {
+                eprintln!("WARNING: image pool size limit of {} bytes reached. further crash images are dropped.", self.size_max);
             }
-            let path = format!("{}/{}.raw", self.crash_dir, hash.0.to_hex());
-            let mut file = File::create(path.as_str()).with_context(|| format!("could not open {}", path))?;
-            file.write_all(data).context("could not dump image")?;
-            Ok((true, hash))
-        } else {
-            Ok((false, hash))
+            self.dropped += 1;
+            return Ok(None);
         }
+        self.size += delta.size();
+        delta.write(format!("{}/{}.delta", self.crash_dir, hash).as_str()).context("could not dump image")?;
+        self.hashes.insert(hash.clone());
+        Ok(Some(hash))
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
     }
 }
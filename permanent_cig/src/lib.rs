@@ -1,13 +1,15 @@
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::io::{BufReader, BufWriter};
 use std::fs::File;
+use std::path::Path;
 use std::time::SystemTime;
 use std::marker::PhantomData;
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use serde::{Serialize, Deserialize};
 use permanent_common::action::Action;
-use permanent_common::config::{VmConfig, TestConfig};
+use permanent_common::config::{VmConfig, TestConfig, TraceConfig, TraceType};
 use permanent_common::profiler::{Profile, Measurement};
-use permanent_common::trace::{TraceEntry, PmemEvent, NvmeEvent, parse_trace_file_bin};
+use permanent_common::trace::{TraceEntry, PmemEvent, NvmeEvent, IndexedTrace, parse_trace_file_bin};
 
 mod set;
 
@@ -15,19 +17,81 @@ mod image;
 use image::{CrashHash, ImagePool};
 
 mod models;
-use models::{X86PersistentMemory, NvmeDevice};
+use models::{apply_entry, complete_barrier, Applied, CrashImages, Device, X86PersistentMemory, NvmeDevice};
+pub use models::{ModelParams, AppliedStore};
 
-enum CrashPersistenceType {
+mod explain;
+pub use explain::{explain, describe_trace_entries};
+
+mod stores;
+pub use stores::CrashImageStores;
+
+#[cfg(test)]
+mod testing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrashPersistenceType {
+    /// there were no unpersisted stores
     NoWrites,
     NothingPersisted,
-    FullyPersisted, // { /* TODO */ },
-    StrictSubsetPersisted, // { /* TODO */ }
+    FullyPersisted,
+    StrictSubsetPersisted,
+}
+
+impl CrashPersistenceType {
+    fn of(applied: usize, unpersisted: usize) -> Self {
+        if unpersisted == 0 {
+            CrashPersistenceType::NoWrites
+        } else if applied == 0 {
+            CrashPersistenceType::NothingPersisted
+        } else if applied == unpersisted {
+            CrashPersistenceType::FullyPersisted
+        } else {
+            CrashPersistenceType::StrictSubsetPersisted
+        }
+    }
+}
+
+/// How a crash image was generated. Written to `pmem_metadata.index` and `nvme_metadata.index`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashMetadata {
+    pub trace_entry_id: usize,
+    pub prev_checkpoint_value: Option<u8>,
+    pub persistence_type: CrashPersistenceType,
+    /// the unpersisted stores or writes applied to the persisted content, in the order of
+    /// application
+    pub applied: Vec<AppliedStore>,
+}
+
+impl CrashMetadata {
+    /// ids of the trace entries with applied stores, in the order of application
+    pub fn applied_ids(&self) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        self.applied.iter().map(|store| store.id).filter(|id| seen.insert(*id)).collect()
+    }
 }
 
-struct CrashMetadata {
-    trace_entry_id: usize,
-    prev_checkpoint_value: Option<u8>,
-    persistence_type: CrashPersistenceType,
+/// The metadata records of a crash image by device ("pmem" or "nvme"). Fails if the image is not
+/// in any metadata index of the work dir.
+pub fn crash_metadata(work_dir: &String, hash: &CrashHash) -> Result<Vec<(&'static str, Vec<CrashMetadata>)>> {
+    let mut found = Vec::new();
+    for device in ["pmem", "nvme"] {
+        let path = format!("{}/{}_metadata.index", work_dir, device);
+        if !Path::new(path.as_str()).exists() {
+            continue;
+        }
+        let file = File::open(path.as_str()).with_context(|| format!("could not open {}", path))?;
+        let mut metadata: BTreeMap<CrashHash, Vec<CrashMetadata>> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("could not parse {}", path))?;
+        if let Some(records) = metadata.remove(hash) {
+            found.push((device, records));
+        }
+    }
+    if found.is_empty() {
+        bail!("{} is not a crash image of this work dir", hash);
+    }
+    Ok(found)
 }
 
 // TODO has_changed optimization
@@ -35,8 +99,53 @@ struct DeviceData<D> {
     device: D,
     changed: bool,
     last_generated_index: Option<usize>,
-    generated: HashMap<usize, HashSet<CrashHash>>,
-    // metadata: HashMap<blake3::Hash, Vec<CrashMetadata>>,
+    generated: BTreeMap<usize, BTreeSet<CrashHash>>,
+    metadata: BTreeMap<CrashHash, Vec<CrashMetadata>>,
+}
+
+impl<D> DeviceData<D> {
+    fn new(device: D) -> Self {
+        Self {
+            device,
+            changed: true,
+            last_generated_index: None,
+            generated: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    fn record_generated(&mut self, trace_entry_id: usize, prev_checkpoint_value: Option<u8>, images: CrashImages, unpersisted: usize) {
+        let mut hashes = BTreeSet::new();
+        for (hash, applied) in images {
+            let persistence_type = CrashPersistenceType::of(applied.len(), unpersisted);
+            self.metadata.entry(hash.clone()).or_default().push(CrashMetadata {
+                trace_entry_id,
+                prev_checkpoint_value,
+                persistence_type,
+                applied,
+            });
+            hashes.insert(hash);
+        }
+        self.changed = false;
+        self.last_generated_index = Some(trace_entry_id);
+        self.generated.insert(trace_entry_id, hashes);
+    }
+
+    /// Nothing has changed since the last generation, so its images are possible here as well.
+    fn reuse_last_generated(&mut self, trace_entry_id: usize, prev_checkpoint_value: Option<u8>) {
+        let last_index = self.last_generated_index.expect("no last_generated_index");
+        let last_images = self.generated.get(&last_index)
+                .expect("last_generated_index hashes not found")
+                .clone();
+        for hash in last_images.iter() {
+            let records = self.metadata.get_mut(hash).expect("no metadata for generated image");
+            let last = records.iter().find(|record| record.trace_entry_id == last_index)
+                .expect("no metadata for last generated image")
+                .clone();
+            records.push(CrashMetadata { trace_entry_id, prev_checkpoint_value, ..last });
+        }
+        self.generated.insert(trace_entry_id, last_images);
+    }
 }
 
 pub struct CrashImageGenerator {
@@ -50,72 +159,146 @@ pub struct CrashImageGenerator {
     rng: fastrand::Rng,
 }
 
-const POOL_LIMIT: usize = 20*1024*1024*1024;
+fn remove_dir(path: &String) -> Result<(), std::io::Error> {
+    if Path::new(path).exists() {
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+fn remove_file(path: &String) -> Result<(), std::io::Error> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Make sure that the traces the crash images are generated from belong to the configs of the work
+/// dir. Changes of the `model` sections are fine. Call it before removing the outputs of a previous
+/// run, which cannot be generated again otherwise.
+pub fn check_traces(work_dir: &String) -> Result<()> {
+    let analyse = TraceConfig::new(work_dir, TraceType::Analyse).trace_path();
+    // the read trace is optional
+    let read = TraceConfig::new(work_dir, TraceType::PostSuccess { pmem_hash: None, nvme_hash: None }).trace_path();
+    let read = Some(read).filter(|path| Path::new(path.as_str()).exists());
+    for path in std::iter::once(analyse).chain(read) {
+        let file = File::open(path.as_str()).with_context(|| format!("could not open trace {}", path))?;
+        parse_trace_file_bin(BufReader::new(file))?.header.check_work_dir(work_dir)
+            .with_context(|| format!("trace {} does not belong to the work dir", path))?;
+    }
+    Ok(())
+}
+
+/// Remove the crash images and index files of a previous run.
+pub fn remove_outputs(work_dir: &String) -> Result<(), std::io::Error> {
+    let make_path = |suffix| format!("{}/{}", work_dir, suffix);
+    remove_dir(&make_path("crash_images"))?;
+    remove_file(&make_path("pmem.index"))?;
+    remove_file(&make_path("nvme.index"))?;
+    remove_file(&make_path("checkpoint.index"))?;
+    remove_file(&make_path("pmem_metadata.index"))?;
+    remove_file(&make_path("nvme_metadata.index"))?;
+    remove_file(&make_path("model.yaml"))?;
+    // the results of the tester belong to the removed crash images
+    remove_file(&make_path("states.log"))?;
+    remove_dir(&make_path("minimized"))?;
+    Ok(())
+}
 
 impl CrashImageGenerator {
     pub fn new(work_dir: &String, vm_config: &VmConfig, test_config: &TestConfig) -> Self {
         let (p, n) = vm_config.have_pmem_nvme();
+        let params = ModelParams::from_configs(vm_config, test_config).expect("invalid model config");
+        params.write(work_dir).expect("could not write model parameters");
+        println!("seed: {}", params.seed);
         Self {
             work_dir: work_dir.clone(),
             vm_config: vm_config.clone(),
             test_config: test_config.clone(),
 
-            pool: ImagePool::with_limit(work_dir, POOL_LIMIT).unwrap(),
-            pmem: p.then(|| DeviceData {
-                device: X86PersistentMemory::new(std::fs::read(format!("{}/pmem_base.raw", &work_dir).as_str()).unwrap()),
-                changed: true,
-                last_generated_index: None,
-                generated: HashMap::new(),
-            }),
-            nvme: n.then(|| DeviceData {
-                device: NvmeDevice::new(std::fs::read(format!("{}/nvme_base.raw", &work_dir).as_str()).unwrap()),
-                changed: true,
-                last_generated_index: None,
-                generated: HashMap::new(),
-            }),
-            rng: fastrand::Rng::new(),
+            pool: ImagePool::with_limit(work_dir, params.pool_limit).unwrap(),
+            pmem: p.then(|| DeviceData::new(
+                X86PersistentMemory::new(std::fs::read(format!("{}/pmem_base.raw", &work_dir).as_str()).unwrap(), &params),
+            )),
+            nvme: n.then(|| DeviceData::new(
+                NvmeDevice::new(std::fs::read(format!("{}/nvme_base.raw", &work_dir).as_str()).unwrap(), &params),
+            )),
+            rng: fastrand::Rng::with_seed(params.seed),
         }
     }
 
-    fn generate_crash_images_at(&mut self, trace_entry_id: usize) {
+    /// Load the read trace of the post-success stage. Afterwards, only unpersisted lines and blocks
+    /// that are read during recovery are varied in crash images.
+    pub fn load_read_set(&mut self) -> Result<()> {
+        let trace_config = TraceConfig::new(&self.work_dir, TraceType::PostSuccess { pmem_hash: None, nvme_hash: None });
+        let trace_file = File::open(trace_config.trace_path().as_str())
+            .with_context(|| format!("could not open read trace {}", trace_config.trace_path()))?;
+        let mut trace = parse_trace_file_bin(BufReader::new(trace_file)).context("invalid read trace")?;
+        trace.header.check_work_dir(&self.work_dir)?;
+        // reads before checkpoint 255 belong to the boot process, not to recovery
+        let mut had_init = false;
+        for entry in trace.by_ref() {
+            match entry.context("could not parse read trace")? {
+                TraceEntry::Pmem { event: PmemEvent::Read { address, size, content: _ }, .. } => {
+                    if let Some(pmem) = self.pmem.as_mut().filter(|_| had_init) {
+                        pmem.device.record_read(address as usize, size as usize);
+                    }
+                },
+                TraceEntry::Nvme { id: _, event: NvmeEvent::Read { offset, length } } => {
+                    if let Some(nvme) = self.nvme.as_mut().filter(|_| had_init) {
+                        nvme.device.record_read(offset as usize, length as usize);
+                    }
+                },
+                TraceEntry::Checkpoint { id: _, value: 255 } => { had_init = true; },
+                _ => { },
+            }
+        }
+        if !trace.is_terminated() {
+            bail!("read trace is truncated. the recovery VM might have been killed.");
+        }
+        if !had_init {
+            bail!("read trace does not contain the initial checkpoint");
+        }
+
+        // a device without any recorded reads has an empty read set, not an unknown one
+        if let Some(pmem) = self.pmem.as_mut() {
+            let read_lines = pmem.device.read_lines.get_or_insert_with(HashSet::new);
+            println!("pmem: {} lines read during recovery", read_lines.len());
+        }
+        if let Some(nvme) = self.nvme.as_mut() {
+            let read_blocks = nvme.device.read_blocks.get_or_insert_with(HashSet::new);
+            println!("nvme: {} blocks read during recovery", read_blocks.len());
+        }
+        Ok(())
+    }
+
+    fn generate_crash_images_at(&mut self, trace_entry_id: usize, prev_checkpoint_value: Option<u8>) {
         println!("generate crash images at id {}", trace_entry_id);
         let (p, n) = self.vm_config.have_pmem_nvme();
         if p {
             if self.get_pmem_mut().changed {
-                let nothing_hash = self.pmem.as_ref().unwrap().device.generate_nothing_persisted_image(&mut self.pool);
-                let everything_hash = self.pmem.as_ref().unwrap().device.generate_everything_persisted_image(&mut self.pool);
-                let mut hashes = self.pmem.as_ref().unwrap().device.generate_random_images(&mut self.pool, &mut self.rng);
-                hashes.insert(nothing_hash);
-                hashes.insert(everything_hash);
-                self.get_pmem_mut().changed = false;
-                self.get_pmem_mut().last_generated_index = Some(trace_entry_id);
-                self.get_pmem_mut().generated.insert(trace_entry_id, hashes.clone());
+                let device = &self.pmem.as_ref().unwrap().device;
+                let nothing = device.generate_nothing_persisted_image(&mut self.pool);
+                let everything = device.generate_everything_persisted_image(&mut self.pool);
+                let mut images = device.generate_random_images(&mut self.pool, &mut self.rng);
+                images.extend(nothing.into_iter().chain(everything));
+                let unpersisted = device.unpersisted_store_count();
+                self.get_pmem_mut().record_generated(trace_entry_id, prev_checkpoint_value, images, unpersisted);
             } else {
-                // reuse last set of images
-                let last_index = self.pmem.as_ref().unwrap().last_generated_index.expect("no last_generated_index");
-                let last_images = self.pmem.as_ref().unwrap().generated.get(&last_index)
-                        .expect("last_generated_index hashes not found")
-                        .clone();
-                self.get_pmem_mut().generated.insert(trace_entry_id, last_images);
+                self.get_pmem_mut().reuse_last_generated(trace_entry_id, prev_checkpoint_value);
             }
         }
         if n {
             if self.get_nvme_mut().changed {
-                let nothing_hash = self.nvme.as_ref().unwrap().device.generate_nothing_persisted_image(&mut self.pool);
-                let everything_hash = self.nvme.as_ref().unwrap().device.generate_everything_persisted_image(&mut self.pool);
-                let mut hashes = self.nvme.as_ref().unwrap().device.generate_random_images(&mut self.pool, &mut self.rng);
-                hashes.insert(nothing_hash);
-                hashes.insert(everything_hash);
-                self.get_nvme_mut().changed = false;
-                self.get_nvme_mut().last_generated_index = Some(trace_entry_id);
-                self.get_nvme_mut().generated.insert(trace_entry_id, hashes.clone());
+                let device = &self.nvme.as_ref().unwrap().device;
+                let nothing = device.generate_nothing_persisted_image(&mut self.pool);
+                let everything = device.generate_everything_persisted_image(&mut self.pool);
+                let mut images = device.generate_random_images(&mut self.pool, &mut self.rng);
+                images.extend(nothing.into_iter().chain(everything));
+                let unpersisted = device.unpersisted_store_count();
+                self.get_nvme_mut().record_generated(trace_entry_id, prev_checkpoint_value, images, unpersisted);
             } else {
-                // reuse last set of images
-                let last_index = self.nvme.as_ref().unwrap().last_generated_index.expect("no last_generated_index");
-                let last_images = self.nvme.as_ref().unwrap().generated.get(&last_index)
-                        .expect("last_generated_index hashes not found")
-                        .clone();
-                self.get_nvme_mut().generated.insert(trace_entry_id, last_images);
+                self.get_nvme_mut().reuse_last_generated(trace_entry_id, prev_checkpoint_value);
             }
         }
     }
@@ -131,139 +314,288 @@ impl CrashImageGenerator {
     pub fn replay_trace(&mut self) { // TODO use anyhow results
         let mut had_init = false;
         let mut prev_checkpoint_value: Option<u8> = None;
-        let mut checkpoint_ids: HashMap<u8, usize> = HashMap::new();
+        let mut checkpoint_ids: BTreeMap<u8, usize> = BTreeMap::new();
 
         let checkpoint_range = self.test_config.checkpoint_range.0
             .. self.test_config.checkpoint_range.1;
         let within_checkpoint_range = |maybe_value: Option<u8>| { maybe_value.is_some_and(|value| checkpoint_range.contains(&value)) };
 
-        // TODO path
-        let trace_file = File::open(format!("{}/analyse/trace.bin", self.work_dir).as_str())
+        let trace_path = TraceConfig::new(&self.work_dir, TraceType::Analyse).trace_path();
+        let trace_file = File::open(trace_path.as_str())
             .expect("could not open trace file");
-        for entry in parse_trace_file_bin(BufReader::new(trace_file)) {
-            match entry.unwrap() {
-                TraceEntry::Pmem { id, event } => {
-                    match event {
-                        PmemEvent::Read  { .. } => { },
-                        PmemEvent::Write { address, size: _, content, non_temporal } => {
-                            if !had_init {
-                                panic!("pmem event before test script");
-                            }
-                            self.get_pmem_mut().changed = true;
-                            self.get_pmem_mut().device.write(address as usize, content.as_slice(), non_temporal);
-                        },
-                        PmemEvent::Clflush { address } => {
-                            if !had_init {
-                                panic!("pmem event before test script");
-                            }
-                            self.get_pmem_mut().device.clwb(address as usize, None);
-                            // approximate Clflush by adding a fence
-                            // this necessitates crash image generation.
-                            if within_checkpoint_range(prev_checkpoint_value) {
-                                // we do not generate crash images before the first or after the
-                                // last checkpoint.
-                                if !self.get_pmem_mut().device.pending_lines.is_empty() {
-                                    self.generate_crash_images_at(id as usize);
-                                    self.get_pmem_mut().changed = true; // after a fence with flushes, different
-                                                         // crash images are possible
-                                }
-                            }
-                            self.get_pmem_mut().device.fence();
-                        },
-                        PmemEvent::Clflushopt { address } => {
-                            if !had_init {
-                                panic!("pmem event before test script");
-                            }
-                            self.get_pmem_mut().device.clwb(address as usize, None);
-                        },
-                        PmemEvent::Clwb { address } => {
-                            if !had_init {
-                                panic!("pmem event before test script");
-                            }
-                            self.get_pmem_mut().device.clwb(address as usize, None);
-                        },
-                        PmemEvent::Wbinvd => {
-                            if had_init { // yes, there should be no ! here.
-                                panic!("wbinvd should not appear during normal test execution")
-                            }
-                        },
-                        PmemEvent::Fence => {
-                            if within_checkpoint_range(prev_checkpoint_value) {
-                                // we do not generate crash images before the first or after the
-                                // last checkpoint.
-                                if !self.get_pmem_mut().device.pending_lines.is_empty() {
-                                    self.generate_crash_images_at(id as usize);
-                                    self.get_pmem_mut().changed = true; // after a fence with flushes, different
-                                                         // crash images are possible
-                                }
-                            }
-                            self.get_pmem_mut().device.fence();
-                        },
-                    }
-                },
-                TraceEntry::Nvme { id, event } => {
-                    match event {
-                        NvmeEvent::Read { .. } => { },
-                        NvmeEvent::Write { offset, length: _, data } => {
-                            if !had_init {
-                                panic!("nvme event before test script");
-                            }
-                            self.get_nvme_mut().changed = true;
-                            self.get_nvme_mut().device.write(offset as usize, data);
-                        }
-                        NvmeEvent::Flush => {
-                            if !had_init {
-                                panic!("nvme event before test script");
-                            }
-                            if within_checkpoint_range(prev_checkpoint_value) {
-                                if !self.get_nvme_mut().device.unpersisted_content.is_empty() {
-                                    self.generate_crash_images_at(id as usize);
-                                    self.get_nvme_mut().changed = true; // after flush with writes, different
-                                                         // crash images are possible
-                                }
-                            }
-                            self.get_nvme_mut().device.flush();
-                        },
-                    }
-                },
-                TraceEntry::Checkpoint { id, value } => {
-                    if value == 255 {
-                        had_init = true;
-                    } else {
-                        prev_checkpoint_value = Some(value);
-                        if value > 0 && !checkpoint_ids.contains_key(&(value - 1)) { // TODO do we want this?
-                            panic!("non-contiguous checkpoints; missing: {}", value - 1);
-                        }
-                        if checkpoint_ids.insert(value, id as usize).is_some() {
-                            panic!("duplicate checkpoint value: {}", value);
-                        }
-                        // we create crash images at every checkpoint including the last (for SFS)
-                        if within_checkpoint_range(prev_checkpoint_value) || self.test_config.checkpoint_range.1 == value {
-                            self.generate_crash_images_at(id as usize);
+        let mut trace = parse_trace_file_bin(BufReader::new(trace_file)).expect("invalid trace file");
+        trace.header.check_work_dir(&self.work_dir).expect("trace does not belong to the work dir");
+        // entries after the last checkpoint cannot lead to crash images. with an index, we know
+        // where that is and stop decoding there.
+        let end_id = IndexedTrace::open(trace_path.as_str()).ok()
+            .and_then(|indexed| indexed.index.checkpoint_id(self.test_config.checkpoint_range.1));
+        for entry in trace.by_ref() {
+            let entry = entry.unwrap();
+            if end_id.is_some_and(|end_id| entry.id() > end_id) {
+                break;
+            }
+            let id = entry.id() as usize;
+            let checkpoint = match entry {
+                TraceEntry::Checkpoint { id: _, value } if value != 255 => Some(value),
+                _ => None,
+            };
+            let applied = apply_entry(entry, &mut had_init, self.pmem.as_mut().map(|pmem| &mut pmem.device), self.nvme.as_mut().map(|nvme| &mut nvme.device))
+                .unwrap();
+            match applied {
+                Applied::Nothing => { },
+                Applied::Write(Device::Pmem) => self.get_pmem_mut().changed = true,
+                Applied::Write(Device::Nvme) => self.get_nvme_mut().changed = true,
+                Applied::Barrier(device) => {
+                    let pending = match device {
+                        Device::Pmem => !self.get_pmem_mut().device.pending_lines.is_empty(),
+                        Device::Nvme => !self.get_nvme_mut().device.unpersisted_content.is_empty(),
+                    };
+                    // we do not generate crash images before the first or after the last
+                    // checkpoint.
+                    if within_checkpoint_range(prev_checkpoint_value) && pending {
+                        self.generate_crash_images_at(id, prev_checkpoint_value);
+                        // after a barrier with pending stores, different crash images are possible
+                        match device {
+                            Device::Pmem => self.get_pmem_mut().changed = true,
+                            Device::Nvme => self.get_nvme_mut().changed = true,
                         }
                     }
+                    complete_barrier(device, self.pmem.as_mut().map(|pmem| &mut pmem.device), self.nvme.as_mut().map(|nvme| &mut nvme.device));
                 },
             }
+            if let Some(value) = checkpoint {
+                prev_checkpoint_value = Some(value);
+                if value > 0 && !checkpoint_ids.contains_key(&(value - 1)) { // TODO do we want this?
+                    panic!("non-contiguous checkpoints; missing: {}", value - 1);
+                }
+                if checkpoint_ids.insert(value, id).is_some() {
+                    panic!("duplicate checkpoint value: {}", value);
+                }
+                // we create crash images at every checkpoint including the last (for SFS)
+                if within_checkpoint_range(prev_checkpoint_value) || self.test_config.checkpoint_range.1 == value {
+                    self.generate_crash_images_at(id, prev_checkpoint_value);
+                }
+            }
+        }
+
+        // the index is only written for complete traces
+        if end_id.is_none() && !trace.is_terminated() {
+            panic!("ERROR: the trace is truncated. was QEMU killed? trace again.");
         }
-        
         if !checkpoint_ids.contains_key(&self.test_config.checkpoint_range.1) {
             panic!("ERROR: not all checkpoints are present in the trace. abort.")
         }
 
+        if self.pool.dropped() > 0 {
+            eprintln!("WARNING: {} crash images were dropped because of the image pool size limit", self.pool.dropped());
+        }
+
         // write index information
         let (p, n) = self.vm_config.have_pmem_nvme();
         if p {
             let pmem = self.pmem.as_ref().unwrap();
             let file = File::create(format!("{}/pmem.index", self.work_dir).as_str()).unwrap();
             serde_json::to_writer_pretty(BufWriter::new(file), &pmem.generated).unwrap();
+            let file = File::create(format!("{}/pmem_metadata.index", self.work_dir).as_str()).unwrap();
+            serde_json::to_writer_pretty(BufWriter::new(file), &pmem.metadata).unwrap();
         }
         if n {
             let nvme = self.nvme.as_ref().unwrap();
             let file = File::create(format!("{}/nvme.index", self.work_dir).as_str()).unwrap();
             serde_json::to_writer_pretty(BufWriter::new(file), &nvme.generated).unwrap();
+            let file = File::create(format!("{}/nvme_metadata.index", self.work_dir).as_str()).unwrap();
+            serde_json::to_writer_pretty(BufWriter::new(file), &nvme.metadata).unwrap();
         }
         // write checkpoint information
         let file = File::create(format!("{}/checkpoint.index", self.work_dir).as_str()).unwrap();
         serde_json::to_writer_pretty(BufWriter::new(file), &checkpoint_ids).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use permanent_common::config::{ModelConfig, PmemPlatform};
+    use permanent_common::trace::import_trace_text;
+    use crate::testing::TestWorkDir;
+
+    const VM_CONFIG: &str = r#"
+        fs_type: pmem
+        pmem_start: 0
+        pmem_len: 4096
+        nvme_len: null
+        qemu_path: ""
+        kernel_path: ""
+        initrd_path: ""
+        qemu_args: []
+        trace_cmd_prefix: ""
+        dump_cmd_prefix: ""
+        recovery_cmd: ""
+    "#;
+
+    /// Replay a hand-written trace from `fixtures/` in a fresh work dir with an empty 4 KiB pmem image.
+    fn replay_fixture(name: &str, checkpoint_range: (u8, u8), model: ModelConfig) -> (TestWorkDir, CrashImageGenerator) {
+        let work_dir = TestWorkDir::with_base(name, "pmem_base.raw", 4096);
+        let trace_config = TraceConfig::new(&work_dir, TraceType::Analyse);
+        std::fs::create_dir_all(trace_config.trace_dir()).unwrap();
+        let fixture = format!("{}/fixtures/{}.jsonl", env!("CARGO_MANIFEST_DIR"), name);
+        import_trace_text(fixture.as_str(), trace_config.trace_path().as_str(), false).unwrap();
+
+        let vm_config: VmConfig = serde_yaml::from_str(VM_CONFIG).unwrap();
+        let test_config = TestConfig {
+            trace_cmd_suffix: String::new(),
+            checkpoint_range,
+            dump_cmd_suffix: String::new(),
+            expect: None,
+            model,
+        };
+        let mut cig = CrashImageGenerator::new(&work_dir, &vm_config, &test_config);
+        cig.replay_trace();
+        (work_dir, cig)
+    }
+
+    #[test]
+    fn test_pmem_clwb_fence() {
+        let (work_dir, cig) = replay_fixture("pmem_clwb_fence", (0, 1), ModelConfig::default());
+        let generated = &cig.pmem.as_ref().unwrap().generated;
+        // checkpoint 0: nothing written yet
+        assert_eq!(generated[&1].len(), 1);
+        // fence: each line with or without its store
+        assert_eq!(generated[&5].len(), 4);
+        // checkpoint 1: the first line is persisted, the second one is still open
+        assert_eq!(generated[&6].len(), 2);
+        assert!(generated[&6].is_subset(&generated[&5]));
+
+        // every image at the fence is explained by the stores it contains
+        let metadata = &cig.pmem.as_ref().unwrap().metadata;
+        let mut at_fence: Vec<(CrashPersistenceType, Vec<usize>)> = generated[&5].iter()
+            .map(|hash| metadata[hash].iter().find(|record| record.trace_entry_id == 5).unwrap())
+            .map(|record| (record.persistence_type, record.applied_ids().into_iter().sorted().collect()))
+            .collect();
+        at_fence.sort_by_key(|(_, applied_ids)| applied_ids.clone());
+        assert_eq!(at_fence, vec![
+            (CrashPersistenceType::NothingPersisted, vec![]),
+            (CrashPersistenceType::StrictSubsetPersisted, vec![2]),
+            (CrashPersistenceType::FullyPersisted, vec![2, 3]),
+            (CrashPersistenceType::StrictSubsetPersisted, vec![3]),
+        ]);
+
+        // the stores can be reconstructed from the trace to rebuild every image
+        for hash in generated[&5].iter() {
+            let stores = CrashImageStores::load(&work_dir, &cig.vm_config, hash).unwrap();
+            assert_eq!(&CrashHash::of(stores.build(stores.applied()).as_slice()), hash);
+            assert_eq!(stores.build(&[]), vec![0u8; 4096]);
+        }
+    }
+
+    #[test]
+    fn test_pmem_clflush() {
+        let (work_dir, cig) = replay_fixture("pmem_clflush", (0, 1), ModelConfig::default());
+        let generated = &cig.pmem.as_ref().unwrap().generated;
+        assert_eq!(generated[&4].len(), 4);
+        // checkpoint 1: the first line is persisted by the clflush
+        assert_eq!(generated[&5].len(), 2);
+
+        // the stores are reconstructed up to the clflush, but without its fence
+        for hash in generated[&4].iter() {
+            let stores = CrashImageStores::load(&work_dir, &cig.vm_config, hash).unwrap();
+            assert_eq!(&CrashHash::of(stores.build(stores.applied()).as_slice()), hash);
+        }
+    }
+
+    #[test]
+    fn test_pmem_torn() {
+        let model = ModelConfig { tearing_granularity: Some(1), ..Default::default() };
+        let (work_dir, cig) = replay_fixture("pmem_torn", (0, 1), model);
+        let pmem = cig.pmem.as_ref().unwrap();
+        let at_fence = &pmem.generated[&4];
+        assert!(at_fence.len() > 2);
+        for hash in at_fence.iter() {
+            // the applied parts are recorded, so torn images are rebuilt as generated
+            let stores = CrashImageStores::load(&work_dir, &cig.vm_config, hash).unwrap();
+            let image = stores.build(stores.applied());
+            assert_eq!(&CrashHash::of(image.as_slice()), hash);
+            let applied: Vec<usize> = stores.applied().iter().map(|store| store.address).sorted().collect();
+            let written: Vec<usize> = (70..74).filter(|address| image[*address] != 0).collect();
+            assert_eq!(applied, written);
+        }
+    }
+
+    #[test]
+    fn test_pmem_eadr() {
+        let model = ModelConfig { pmem_platform: Some(PmemPlatform::Eadr), ..Default::default() };
+        let (_work_dir, cig) = replay_fixture("pmem_clwb_fence", (0, 1), model);
+        let pmem = cig.pmem.as_ref().unwrap();
+        // fence: the stores persist in order, so the second one never persists without the first
+        let mut at_fence: Vec<Vec<usize>> = pmem.generated[&5].iter()
+            .map(|hash| pmem.metadata[hash].iter().find(|record| record.trace_entry_id == 5).unwrap().applied_ids())
+            .collect();
+        at_fence.sort();
+        assert_eq!(at_fence, vec![vec![], vec![2], vec![2, 3]]);
+        // checkpoint 1: the fence made both stores durable
+        assert_eq!(pmem.generated[&6].len(), 1);
+    }
+
+    #[test]
+    fn test_model_change() {
+        let work_dir = TestWorkDir::with_base("model_change", "pmem_base.raw", 4096);
+        std::fs::write(format!("{}/vm_config.yaml", *work_dir), VM_CONFIG).unwrap();
+        let write_test_config = |suffix: &str, model: &str| std::fs::write(format!("{}/test_config.yaml", *work_dir),
+            format!("{{ trace_cmd_suffix: \"{}\", checkpoint_range: [0, 1], dump_cmd_suffix: \"\", model: {} }}", suffix, model)).unwrap();
+        write_test_config("", "{ line_granularity: 64 }");
+
+        // the trace records the configs it was captured with
+        let (vm_hash, test_hash) = permanent_common::config::capture_hashes(&work_dir).unwrap();
+        let fixture = std::fs::read_to_string(format!("{}/fixtures/pmem_clwb_fence.jsonl", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let header = format!(r#"{{"pmem_len": 4096, "trace_what": ["pmem_write", "pmem_fence", "pmem_flush", "checkpoint"], "vm_config_hash": "{}", "test_config_hash": "{}"}}"#,
+            vm_hash.unwrap(), test_hash.unwrap());
+        let text = std::iter::once(header.as_str())
+            .chain(fixture.lines().filter(|line| !line.starts_with('#')).skip(1))
+            .join("\n");
+        let text_path = format!("{}/trace.jsonl", *work_dir);
+        std::fs::write(text_path.as_str(), text).unwrap();
+        let trace_config = TraceConfig::new(&work_dir, TraceType::Analyse);
+        std::fs::create_dir_all(trace_config.trace_dir()).unwrap();
+        import_trace_text(text_path.as_str(), trace_config.trace_path().as_str(), false).unwrap();
+
+        // like the cig stage
+        let generate = |work_dir: &String| -> Result<()> {
+            check_traces(work_dir)?;
+            remove_outputs(work_dir)?;
+            let vm_config: VmConfig = serde_yaml::from_str(VM_CONFIG)?;
+            let test_config: TestConfig = serde_yaml::from_str(std::fs::read_to_string(format!("{}/test_config.yaml", work_dir))?.as_str())?;
+            CrashImageGenerator::new(work_dir, &vm_config, &test_config).replay_trace();
+            Ok(())
+        };
+        generate(&work_dir).unwrap();
+
+        // a different model regenerates from the same trace
+        write_test_config("", "{ line_granularity: 8 }");
+        generate(&work_dir).unwrap();
+        assert_eq!(ModelParams::read(&work_dir).unwrap().line_granularity, 8);
+
+        // other changes need a new trace and keep the previous crash images
+        write_test_config("touch /mnt/other", "{ line_granularity: 8 }");
+        assert!(generate(&work_dir).is_err());
+        assert!(Path::new(format!("{}/pmem.index", *work_dir).as_str()).exists());
+    }
+
+    #[test]
+    fn test_seed() {
+        // with a single subset per crash point, the lines are chosen randomly
+        let model = ModelConfig { max_unpersisted_subsets: Some(1), seed: Some(42), ..Default::default() };
+        let read_outputs = |work_dir: &String| ["pmem.index", "pmem_metadata.index", "model.yaml"]
+            .map(|name| std::fs::read(format!("{}/{}", work_dir, name)).unwrap());
+        let (first_dir, _) = replay_fixture("pmem_clwb_fence", (0, 1), model.clone());
+        let (second_dir, _) = replay_fixture("pmem_clwb_fence", (0, 1), model);
+        assert!(read_outputs(&first_dir) == read_outputs(&second_dir));
+
+        // without a seed, the recorded one repeats the run
+        let model = ModelConfig { max_unpersisted_subsets: Some(1), ..Default::default() };
+        let (first_dir, _) = replay_fixture("pmem_clwb_fence", (0, 1), model.clone());
+        let seed = ModelParams::read(&first_dir).unwrap().seed;
+        let (second_dir, _) = replay_fixture("pmem_clwb_fence", (0, 1), ModelConfig { seed: Some(seed), ..model });
+        assert!(read_outputs(&first_dir) == read_outputs(&second_dir));
+    }
+}
@@ -1,39 +1,30 @@
 use std::io::BufReader;
 use std::fs::File;
 use clap::Parser;
-use std::path::Path;
 use permanent_common::action::Action;
 use permanent_common::config::{VmConfig, TestConfig};
-use permanent_cig::CrashImageGenerator;
-
-fn remove_dir(path: &String) -> Result<(), std::io::Error> {
-    if Path::new(path).exists() {
-        std::fs::remove_dir_all(path)?;
-    }
-    Ok(())
-}
-
-fn remove_file(path: &String) -> Result<(), std::io::Error> {
-    if Path::new(path).exists() {
-        std::fs::remove_file(path)?;
-    }
-    Ok(())
-}
+use permanent_cig::{CrashImageGenerator, check_traces, explain, remove_outputs};
 
 fn main() {
     let args = Args::parse();
     let vm_config: VmConfig = serde_yaml::from_reader(BufReader::new(File::open(format!("{}/vm_config.yaml", args.work_dir).as_str()).unwrap())).unwrap();
-    let test_config: TestConfig = serde_yaml::from_reader(BufReader::new(File::open(format!("{}/test_config.yaml", args.work_dir).as_str()).unwrap())).unwrap();
-
-    let make_path = |suffix| format!("{}/{}", args.work_dir, suffix);
+    let mut test_config: TestConfig = serde_yaml::from_reader(BufReader::new(File::open(format!("{}/test_config.yaml", args.work_dir).as_str()).unwrap())).unwrap();
 
+    if let Some(hash) = &args.explain {
+        explain(&args.work_dir, &vm_config, hash.as_str()).expect("could not explain crash image");
+        return;
+    }
+    if let Some(seed) = args.seed {
+        test_config.model.seed = Some(seed);
+    }
     if args.force {
-        remove_dir(&make_path("crash_images")).unwrap();
-        remove_file(&make_path("pmem.index")).unwrap();
-        remove_file(&make_path("nvme.index")).unwrap();
-        remove_file(&make_path("checkpoint.index")).unwrap();
+        check_traces(&args.work_dir).expect("could not generate crash images");
+        remove_outputs(&args.work_dir).unwrap();
     }
     let mut cig = CrashImageGenerator::new(&args.work_dir, &vm_config, &test_config);
+    if args.read_set {
+        cig.load_read_set().expect("could not load read set");
+    }
     cig.replay_trace();
 }
 
@@ -42,4 +33,14 @@ pub struct Args {
     work_dir: String,
     #[clap(short, long, action)]
     force: bool,
+    /// only vary lines and blocks that are read during recovery (needs the post-success trace)
+    #[clap(short, long, action)]
+    read_set: bool,
+    /// print the trace position and the stores of a generated crash image instead of generating
+    #[clap(long, value_name = "HASH")]
+    explain: Option<String>,
+    /// seed of the random choice of crash images, overrides the configs. The seed used is
+    /// recorded in model.yaml.
+    #[clap(long)]
+    seed: Option<u64>,
 }
@@ -1,22 +1,44 @@
 use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::Range;
 use std::marker::Sized;
 use itertools::Itertools;
 
-use permanent_common::trace::{PmemEvent, NvmeEvent};
+use serde::{Serialize, Deserialize};
+use permanent_common::config::{VmConfig, TestConfig, PmemPlatform};
+use permanent_common::trace::{TraceEntry, PmemEvent, NvmeEvent};
 use crate::image::{ImagePool, CrashHash};
 use crate::set;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 #[derive(Debug, Clone)]
 pub struct Store {
+    /// id of the trace entry the store comes from
+    pub id: usize,
     pub address: usize,
     pub data: Vec<u8>,
 }
 
+/// A store applied to a crash image. Stores split into several parts are applied part by part,
+/// so a torn store only has some of its parts applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct AppliedStore {
+    /// id of the trace entry the store comes from
+    pub id: usize,
+    pub address: usize,
+    pub len: usize,
+}
+
+/// Crash images and the stores applied on top of the persisted content, in the order of
+/// application.
+pub type CrashImages = HashMap<CrashHash, Vec<AppliedStore>>;
+
 impl Store {
+    pub fn applied(&self) -> AppliedStore {
+        AppliedStore { id: self.id, address: self.address, len: self.data.len() }
+    }
+
     pub fn address_start(&self) -> usize {
         self.address
     }
@@ -67,6 +89,24 @@ impl OrderedWriteLine {
         &self.writes[self.flushed_index..]
     }
 
+    /// The ways the line can be partially written back, as indices into `all_writes`: a prefix of
+    /// the stores, of which the last one may be torn and persist any non-empty subset of its
+    /// pieces. The last way writes back everything.
+    pub fn partial_writes(&self) -> Vec<Vec<usize>> {
+        let mut ways = Vec::new();
+        let mut start = 0;
+        while start < self.writes.len() {
+            // the pieces of a torn store are consecutive and have the id of the store
+            let id = self.writes[start].id;
+            let end = start + self.writes[start..].iter().take_while(|store| store.id == id).count();
+            for pieces in (start..end).powerset().skip(1) {
+                ways.push((0..start).chain(pieces).collect());
+            }
+            start = end;
+        }
+        ways
+    }
+
     pub fn drain_flushed_writes(&mut self) -> std::vec::Drain<'_, Store> {
         let idx = self.flushed_index;
         self.flushed_index = 0;
@@ -82,6 +122,90 @@ impl OrderedWriteLine {
     }
 }
 
+/// Parameters of the persistency models, from the `model` sections of the test and vm configs.
+/// The effective values are written to `model.yaml` in the work dir.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelParams {
+    pub line_granularity: usize,
+    pub max_unpersisted_subsets: usize,
+    pub max_partial_flushes_count: usize,
+    pub pmem_platform: PmemPlatform,
+    pub max_eadr_prefixes: usize,
+    pub tearing_granularity: usize,
+    pub nvme_exhaustive_images_max_amount: usize,
+    pub nvme_random_images_amount: usize,
+    pub nvme_atomic_block_size_shift: usize,
+    pub pool_limit: usize,
+    /// seed of the random choice of crash images
+    pub seed: u64,
+}
+
+impl Default for ModelParams {
+    fn default() -> Self {
+        Self {
+            line_granularity: LINE_GRANULARITY,
+            max_unpersisted_subsets: MAX_UNPERSISTED_SUBSETS,
+            max_partial_flushes_count: MAX_PARTIAL_FLUSHES_COUNT,
+            pmem_platform: PmemPlatform::Adr,
+            max_eadr_prefixes: MAX_EADR_PREFIXES,
+            tearing_granularity: TEARING_GRANULARITY,
+            nvme_exhaustive_images_max_amount: NVME_EXHAUSTIVE_IMAGES_MAX_AMOUNT,
+            nvme_random_images_amount: NVME_RANDOM_IMAGES_AMOUNT,
+            nvme_atomic_block_size_shift: NVME_ATOMIC_BLOCK_SIZE_SHIFT,
+            pool_limit: POOL_LIMIT,
+            seed: 0,
+        }
+    }
+}
+
+impl ModelParams {
+    pub fn from_configs(vm_config: &VmConfig, test_config: &TestConfig) -> Result<Self> {
+        let config = test_config.model.or(&vm_config.model);
+        let default = Self::default();
+        let params = Self {
+            line_granularity: config.line_granularity.unwrap_or(default.line_granularity),
+            max_unpersisted_subsets: config.max_unpersisted_subsets.unwrap_or(default.max_unpersisted_subsets),
+            max_partial_flushes_count: config.max_partial_flushes_count.unwrap_or(default.max_partial_flushes_count),
+            pmem_platform: config.pmem_platform.unwrap_or(default.pmem_platform),
+            max_eadr_prefixes: config.max_eadr_prefixes.unwrap_or(default.max_eadr_prefixes),
+            tearing_granularity: config.tearing_granularity.unwrap_or(default.tearing_granularity),
+            nvme_exhaustive_images_max_amount: config.nvme_exhaustive_images_max_amount.unwrap_or(default.nvme_exhaustive_images_max_amount),
+            nvme_random_images_amount: config.nvme_random_images_amount.unwrap_or(default.nvme_random_images_amount),
+            nvme_atomic_block_size_shift: config.nvme_atomic_block_size_shift.unwrap_or(default.nvme_atomic_block_size_shift),
+            pool_limit: config.pool_limit.unwrap_or(default.pool_limit),
+            // recorded in model.yaml, so that a run can be repeated
+            seed: config.seed.unwrap_or_else(|| fastrand::u64(..)),
+        };
+        if !matches!(params.line_granularity, 8 | 64) {
+            bail!("line_granularity must be 8 or 64, not {}", params.line_granularity);
+        }
+        if !matches!(params.tearing_granularity, 1 | 2 | 4 | 8) {
+            bail!("tearing_granularity must be 1, 2, 4 or 8, not {}", params.tearing_granularity);
+        }
+        if params.nvme_atomic_block_size_shift >= usize::BITS as usize {
+            bail!("nvme_atomic_block_size_shift {} is too large", params.nvme_atomic_block_size_shift);
+        }
+        Ok(params)
+    }
+
+    /// The parameters a work dir was generated with. Work dirs from before `model.yaml` used the
+    /// defaults.
+    pub fn read(work_dir: &String) -> Result<Self> {
+        let path = format!("{}/model.yaml", work_dir);
+        if !std::path::Path::new(path.as_str()).exists() {
+            return Ok(Self::default());
+        }
+        let file = std::fs::File::open(path.as_str()).with_context(|| format!("could not open {}", path))?;
+        serde_yaml::from_reader(std::io::BufReader::new(file)).with_context(|| format!("could not parse {}", path))
+    }
+
+    pub fn write(&self, work_dir: &String) -> Result<()> {
+        let path = format!("{}/model.yaml", work_dir);
+        let file = std::fs::File::create(path.as_str()).with_context(|| format!("could not create {}", path))?;
+        serde_yaml::to_writer(std::io::BufWriter::new(file), self).with_context(|| format!("could not write {}", path))
+    }
+}
+
 /// x86 memory persistency model.
 ///
 /// writes to the same cache line are always ordered in respect to each other.
@@ -91,49 +215,96 @@ pub struct X86PersistentMemory {
     pub pending_lines: HashSet<usize>,
     /// maps line number (== address / line_granularity) to OrderedWriteLine
     pub unpersisted_content: HashMap<usize, OrderedWriteLine>,
+    /// lines read during recovery. If present, only these lines are varied in crash images.
+    pub read_lines: Option<HashSet<usize>>,
     /// 8 or 64
     line_granularity: usize,
+    max_unpersisted_subsets: usize,
+    max_partial_flushes_count: usize,
+    platform: PmemPlatform,
+    max_eadr_prefixes: usize,
+    /// size of the atomic pieces of misaligned stores, 8 if they only tear at 8-byte boundaries
+    tearing_granularity: usize,
 }
 
+// defaults of ModelParams
 // TODO
 const LINE_GRANULARITY: usize = 64;
 const MAX_UNPERSISTED_SUBSETS: usize = 5;
 const MAX_PARTIAL_FLUSHES_COUNT: usize = 5;
+const MAX_EADR_PREFIXES: usize = 25;
+const TEARING_GRANULARITY: usize = 8;
+const POOL_LIMIT: usize = 20*1024*1024*1024;
+
+/// crash images are stored as deltas against this image of the work dir
+const PMEM_BASE_IMAGE: &str = "pmem_base.raw";
 
 impl X86PersistentMemory {
-    pub fn new(persisted_content: Vec<u8>) -> Self {
+    pub fn new(persisted_content: Vec<u8>, params: &ModelParams) -> Self {
         Self {
             persisted_content,
             pending_lines: HashSet::new(),
             unpersisted_content: HashMap::new(),
-            line_granularity: LINE_GRANULARITY,
+            read_lines: None,
+            line_granularity: params.line_granularity,
+            max_unpersisted_subsets: params.max_unpersisted_subsets,
+            max_partial_flushes_count: params.max_partial_flushes_count,
+            platform: params.pmem_platform,
+            max_eadr_prefixes: params.max_eadr_prefixes,
+            tearing_granularity: params.tearing_granularity,
         }
     }
 
-    pub fn generate_nothing_persisted_image(&self, pool: &mut ImagePool) -> CrashHash {
-        let (_, hash) = pool.persist(self.persisted_content.as_slice()).unwrap();
-        hash
+    /// Mark all lines touched by a read as relevant for crash image generation.
+    pub fn record_read(&mut self, address: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let first_line = address / self.line_granularity;
+        let last_line = (address + size - 1) / self.line_granularity;
+        self.read_lines.get_or_insert_with(HashSet::new).extend(first_line..=last_line);
     }
 
-    pub fn generate_everything_persisted_image(&self, pool: &mut ImagePool) -> CrashHash {
+    pub fn unpersisted_store_count(&self) -> usize {
+        self.unpersisted_content.values().map(|line| line.all_writes().len()).sum()
+    }
+
+    pub fn generate_nothing_persisted_image(&self, pool: &mut ImagePool) -> Option<(CrashHash, Vec<AppliedStore>)> {
+        let hash = pool.persist(PMEM_BASE_IMAGE, self.persisted_content.as_slice()).unwrap()?;
+        Some((hash, Vec::new()))
+    }
+
+    pub fn generate_everything_persisted_image(&self, pool: &mut ImagePool) -> Option<(CrashHash, Vec<AppliedStore>)> {
         let mut img: Vec<u8> = self.persisted_content.clone();
-        for ordered_write_line in self.unpersisted_content.values() {
-            for store in ordered_write_line.all_writes().iter() {
+        let mut applied = Vec::new();
+        // sorted, so that the applied stores do not depend on the hash map order
+        for line_number in self.unpersisted_content.keys().sorted() {
+            for store in self.unpersisted_content[line_number].all_writes().iter() {
                 img[store.address_range()].copy_from_slice(store.data.as_slice());
+                applied.push(store.applied());
             }
         }
-        let (_, hash) = pool.persist(img.as_slice()).unwrap();
-        hash
+        let hash = pool.persist(PMEM_BASE_IMAGE, img.as_slice()).unwrap()?;
+        Some((hash, applied))
     }
 
-    pub fn generate_random_images(&self, pool: &mut ImagePool, rng: &mut fastrand::Rng) -> HashSet<CrashHash> {
+    pub fn generate_random_images(&self, pool: &mut ImagePool, rng: &mut fastrand::Rng) -> CrashImages {
+        if self.platform == PmemPlatform::Eadr {
+            return self.generate_prefix_images(pool, rng);
+        }
         let mut img: Vec<u8> = vec![0u8; self.persisted_content.len()];
-        let mut hashes = HashSet::new();
-
-        let unpersisted_reads_lines: Vec<usize> = self.unpersisted_content.keys().copied().collect(); // TODO heuristic
+        let mut hashes = CrashImages::new();
+
+        // Vinter heuristic: lines that are not read during recovery stay in their persisted state
+        let unpersisted_reads_lines: Vec<usize> = self.unpersisted_content
+            .keys()
+            .copied()
+            .filter(|line_number| self.read_lines.as_ref().is_none_or(|lines| lines.contains(line_number)))
+            .sorted() // the subsets drawn for a seed must not depend on the hash map order
+            .collect();
         if !unpersisted_reads_lines.is_empty() {
             let random_subsets: Vec<Vec<usize>> = if 1usize.checked_shl(unpersisted_reads_lines.len().try_into().unwrap())
-                .is_some_and(|val| val <= (MAX_UNPERSISTED_SUBSETS + 1).try_into().unwrap())
+                .is_some_and(|val| val <= (self.max_unpersisted_subsets + 1).try_into().unwrap())
             {
                 unpersisted_reads_lines
                     .iter()
@@ -144,42 +315,47 @@ impl X86PersistentMemory {
             } else {
                 set::random_subsets(rng, &unpersisted_reads_lines)
                     .filter(|vec| !vec.is_empty())
-                    .take(MAX_UNPERSISTED_SUBSETS)
+                    .take(self.max_unpersisted_subsets)
                     .collect()
             };
             for random_lines in random_subsets {
-                let partial_flushes_count = random_lines
+                let lines_ways: Vec<Vec<Vec<usize>>> = random_lines
                     .iter()
-                    .map(|line_number| self.unpersisted_content[line_number].all_writes().len())
-                    .fold(1, |acc, x| acc * x);
-                let line_partial_writes: Vec<Vec<usize>> = random_lines
+                    .map(|line_number| self.unpersisted_content[line_number].partial_writes())
+                    .collect();
+                let partial_flushes_count = lines_ways
                     .iter()
-                    .map(|line_number| {
-                        let writes_count = self.unpersisted_content[line_number].all_writes().len();
-                        if partial_flushes_count > MAX_PARTIAL_FLUSHES_COUNT {
-                            if writes_count <= 1 {
-                                vec![writes_count]
-                            } else {
-                                vec![writes_count, rng.usize(1..writes_count)]
-                            }
+                    .map(|ways| ways.len())
+                    .fold(1, |acc, x| acc * x);
+                let line_partial_writes: Vec<Vec<Vec<usize>>> = lines_ways
+                    .into_iter()
+                    .map(|mut ways| {
+                        if partial_flushes_count > self.max_partial_flushes_count && ways.len() > 1 {
+                            // everything and one random partial write back
+                            let partial = ways[rng.usize(..(ways.len() - 1))].clone();
+                            vec![ways.pop().unwrap(), partial]
                         } else {
-                            (1..=writes_count).collect()
+                            ways
                         }
                     })
                     .collect();
                 for partial_writes_indices in line_partial_writes.iter().multi_cartesian_product()
                 {
                     img[..].copy_from_slice(self.persisted_content.as_slice());
-                    for (line_number, flush_writes_limit) in random_lines
+                    let mut applied = Vec::new();
+                    for (line_number, indices) in random_lines
                         .iter()
                         .copied()
                         .zip(partial_writes_indices.iter().copied())
                     {
-                        for store in self.unpersisted_content[&line_number].all_writes().iter().take(*flush_writes_limit) {
+                        let writes = self.unpersisted_content[&line_number].all_writes();
+                        for store in indices.iter().map(|i| &writes[*i]) {
                             img[store.address_range()].copy_from_slice(store.data.as_slice());
+                            applied.push(store.applied());
+                        }
+                        if let Some(hash) = pool.persist(PMEM_BASE_IMAGE, img.as_slice()).unwrap() {
+                            hashes.entry(hash).or_insert_with(|| applied.clone());
                         }
-                        let (_, hash) = pool.persist(img.as_slice()).unwrap();
-                        hashes.insert(hash);
                     }
                 }
             }
@@ -187,20 +363,55 @@ impl X86PersistentMemory {
         hashes
     }
 
-    pub fn write(&mut self, address: usize, value: &[u8], non_temporal: bool) {
+    /// eADR: every crash image applies a prefix of the unpersisted stores in store order. If there
+    /// are more than `max_eadr_prefixes`, they are sampled.
+    fn generate_prefix_images(&self, pool: &mut ImagePool, rng: &mut fastrand::Rng) -> CrashImages {
+        let mut hashes = CrashImages::new();
+        // Vinter heuristic: lines that are not read during recovery stay in their persisted state
+        let stores: Vec<&Store> = self.unpersisted_content.iter()
+            .filter(|(line_number, _)| self.read_lines.as_ref().is_none_or(|lines| lines.contains(line_number)))
+            .flat_map(|(_, line)| line.all_writes().iter())
+            .sorted_by_key(|store| (store.id, store.address))
+            .collect();
+
+        // the empty and the full prefix are the nothing and everything persisted images
+        let mut lengths: Vec<usize> = (1..stores.len()).collect();
+        if lengths.len() > self.max_eadr_prefixes {
+            rng.shuffle(lengths.as_mut_slice());
+            lengths.truncate(self.max_eadr_prefixes);
+            lengths.sort();
+        }
+        let mut img = self.persisted_content.clone();
+        let mut applied = Vec::new();
+        for length in lengths {
+            for store in stores[applied.len()..length].iter() {
+                img[store.address_range()].copy_from_slice(store.data.as_slice());
+                applied.push(store.applied());
+            }
+            if let Some(hash) = pool.persist(PMEM_BASE_IMAGE, img.as_slice()).unwrap() {
+                hashes.entry(hash).or_insert_with(|| applied.clone());
+            }
+        }
+        hashes
+    }
+
+    pub fn write(&mut self, id: usize, address: usize, value: &[u8], non_temporal: bool) {
         // test to see if we even get larger stores
         assert!(matches!(value.len(), 1 | 2 | 4 | 8));
+        // aligned 8-byte pieces persist atomically. misaligned stores may also tear within them,
+        // then any subset of the pieces may persist, see `OrderedWriteLine::partial_writes`.
+        let piece_size = if address.is_multiple_of(value.len()) { 8 } else { self.tearing_granularity };
         let address_stop = address + value.len();
         let split_address_ranges = {
-            let start = address - address % 8;
-            let stop = if address_stop % 8 == 0 {
+            let start = address - address % piece_size;
+            let stop = if address_stop.is_multiple_of(piece_size) {
                 address_stop
             } else {
-                address_stop + 8 - (address_stop % 8)
+                address_stop + piece_size - (address_stop % piece_size)
             };
             (start..stop)
-                .step_by(8)
-                .map(|a| max(a, address)..min(a + 8, address_stop))
+                .step_by(piece_size)
+                .map(|a| max(a, address)..min(a + piece_size, address_stop))
         };
 
         for address_range in split_address_ranges {
@@ -210,12 +421,14 @@ impl X86PersistentMemory {
                 .entry(line_number)
                 .or_insert_with(OrderedWriteLine::new);
             line.writes.push(Store {
+                id,
                 address: address_range.start,
                 data: value[(address_range.start - address)..(address_range.end - address)].into(),
             });
 
-            // approximation of non-temporal stores
-            if non_temporal {
+            // approximation of non-temporal stores. with eADR, every store is durable once it
+            // leaves the store buffer, which a fence waits for.
+            if non_temporal || self.platform == PmemPlatform::Eadr {
                 self.pending_lines.insert(line_number);
                 // note that for cache line granularity, this is probably not quite correct
                 line.flush_all();
@@ -287,88 +500,128 @@ impl X86PersistentMemory {
 /// all writes that are not separated by a flush may be reordered.
 pub struct NvmeDevice {
     pub persisted_content: Vec<u8>,
-    // NOTE: we do not use a construct like OrderedWriteLines here.
-    // It might happen that when we create permutations, writes to the same block are reordered.
-    // But that doesn't matter because when we take partial permutations, no state can appear
-    // that could not have appeared otherwise.
+    // NOTE: we do not use a construct like OrderedWriteLines here. Crash images apply a prefix
+    // of the writes of each block, which covers every state a block can be in.
     pub unpersisted_content: Vec<Store>,
+    /// blocks read during recovery. If present, only writes to these blocks are varied in crash images.
+    pub read_blocks: Option<HashSet<usize>>,
+    /// enumerate all block states if there are at most this many combinations, sample otherwise
+    pub max_exhaustive_images: usize,
+    random_images_amount: usize,
+    block_size_shift: usize,
 }
 
-// TODO
-const NVME_RANDOM_IMAGES_MAX_AMOUNT: Option<usize> = Some(25);
-
+// defaults of ModelParams
+const NVME_EXHAUSTIVE_IMAGES_MAX_AMOUNT: usize = 256;
+const NVME_RANDOM_IMAGES_AMOUNT: usize = 25;
 const NVME_ATOMIC_BLOCK_SIZE_SHIFT: usize = 9;
-const NVME_ATOMIC_BLOCK_SIZE: usize = 1 << NVME_ATOMIC_BLOCK_SIZE_SHIFT;
-const NVME_ATOMIC_BLOCK_SIZE_MASK: usize = (1 << NVME_ATOMIC_BLOCK_SIZE_SHIFT) - 1;
+
+const NVME_BASE_IMAGE: &str = "nvme_base.raw";
 
 impl NvmeDevice {
-    pub fn new(persisted_content: Vec<u8>) -> Self {
+    pub fn new(persisted_content: Vec<u8>, params: &ModelParams) -> Self {
         Self {
             persisted_content,
             unpersisted_content: Vec::new(),
+            read_blocks: None,
+            max_exhaustive_images: params.nvme_exhaustive_images_max_amount,
+            random_images_amount: params.nvme_random_images_amount,
+            block_size_shift: params.nvme_atomic_block_size_shift,
         }
     }
 
-    pub fn generate_nothing_persisted_image(&self, pool: &mut ImagePool) -> CrashHash {
-        let (_, hash) = pool.persist(self.persisted_content.as_slice()).unwrap();
-        hash
+    /// size of atomically written blocks
+    fn block_size(&self) -> usize {
+        1 << self.block_size_shift
+    }
+
+    /// Mark all blocks touched by a read as relevant for crash image generation.
+    pub fn record_read(&mut self, offset: usize, length: usize) {
+        if length == 0 {
+            return;
+        }
+        let first_block = offset >> self.block_size_shift;
+        let last_block = (offset + length - 1) >> self.block_size_shift;
+        self.read_blocks.get_or_insert_with(HashSet::new).extend(first_block..=last_block);
     }
 
-    pub fn generate_everything_persisted_image(&self, pool: &mut ImagePool) -> CrashHash {
+    pub fn unpersisted_store_count(&self) -> usize {
+        self.unpersisted_content.len()
+    }
+
+    pub fn generate_nothing_persisted_image(&self, pool: &mut ImagePool) -> Option<(CrashHash, Vec<AppliedStore>)> {
+        let hash = pool.persist(NVME_BASE_IMAGE, self.persisted_content.as_slice()).unwrap()?;
+        Some((hash, Vec::new()))
+    }
+
+    pub fn generate_everything_persisted_image(&self, pool: &mut ImagePool) -> Option<(CrashHash, Vec<AppliedStore>)> {
         let mut img: Vec<u8> = self.persisted_content.clone();
         for store in self.unpersisted_content.iter() {
             img[store.address_range()].copy_from_slice(store.data.as_slice());
         }
-        let (_, hash) = pool.persist(img.as_slice()).unwrap();
-        hash
+        let hash = pool.persist(NVME_BASE_IMAGE, img.as_slice()).unwrap()?;
+        Some((hash, self.unpersisted_content.iter().map(|store| store.applied()).collect()))
     }
 
-    pub fn generate_random_images(&self, pool: &mut ImagePool, rng: &mut fastrand::Rng) -> HashSet<CrashHash> {
-        let mut img: Vec<u8> = vec![0u8; self.persisted_content.len()];
-        let mut hashes = HashSet::new();
+    /// Unpersisted writes by block, in program order.
+    fn unpersisted_blocks(&self) -> BTreeMap<usize, Vec<&Store>> {
+        let mut blocks: BTreeMap<usize, Vec<&Store>> = BTreeMap::new();
+        for store in self.unpersisted_content.iter() {
+            blocks.entry(store.address >> self.block_size_shift).or_default().push(store);
+        }
+        blocks
+    }
 
-        if self.unpersisted_content.is_empty() {
+    /// Every block is either in its persisted state or has a prefix of its writes applied. All
+    /// such combinations are enumerated if there are at most `max_exhaustive_images`, otherwise
+    /// `random_images_amount` of them are sampled.
+    pub fn generate_random_images(&self, pool: &mut ImagePool, rng: &mut fastrand::Rng) -> CrashImages {
+        let mut img: Vec<u8> = vec![0u8; self.persisted_content.len()];
+        let mut hashes = CrashImages::new();
+
+        // Vinter heuristic: writes to blocks that are not read during recovery are never applied
+        let blocks: Vec<Vec<&Store>> = self.unpersisted_blocks().into_iter()
+            .filter(|(block, _)| self.read_blocks.as_ref().is_none_or(|blocks| blocks.contains(block)))
+            .map(|(_, writes)| writes)
+            .collect();
+        if blocks.is_empty() {
             return hashes;
         }
 
-        // TODO use exhaustive if there are less than NVME_RANDOM_IMAGES_MAX_AMOUNT exhaustive
-        // images, like vinter does.
-
-        if let Some(amount) = NVME_RANDOM_IMAGES_MAX_AMOUNT {
-            let mut indices: Vec<usize> = (0..self.unpersisted_content.len()).collect();
-            for _ in 0..amount {
-                rng.shuffle(indices.as_mut_slice());
-                let partial_index = rng.usize(1..=self.unpersisted_content.len());
-                img[..].copy_from_slice(self.persisted_content.as_slice());
-                for store in indices[..partial_index].iter().map(|idx| &self.unpersisted_content[*idx]) {
-                    img[store.address_range()].copy_from_slice(store.data.as_slice());
-                }
-                let (_, hash) = pool.persist(img.as_slice()).unwrap();
-                hashes.insert(hash);
-            }
+        let combinations = blocks.iter().try_fold(1usize, |acc, writes| acc.checked_mul(writes.len() + 1));
+        let prefix_lengths: Vec<Vec<usize>> = if combinations.is_some_and(|count| count <= self.max_exhaustive_images) {
+            blocks.iter().map(|writes| 0..=writes.len()).multi_cartesian_product().collect()
         } else {
-            for indices in (0..self.unpersisted_content.len()).permutations(self.unpersisted_content.len()) {
-                img[..].copy_from_slice(self.persisted_content.as_slice());
-                for store in indices.into_iter().map(|idx| &self.unpersisted_content[idx]) {
+            (0..self.random_images_amount)
+                .map(|_| blocks.iter().map(|writes| rng.usize(0..=writes.len())).collect())
+                .collect()
+        };
+        for lengths in prefix_lengths {
+            img[..].copy_from_slice(self.persisted_content.as_slice());
+            let mut applied = Vec::new();
+            for (writes, length) in blocks.iter().zip(lengths) {
+                for store in writes[..length].iter() {
                     img[store.address_range()].copy_from_slice(store.data.as_slice());
-                    // create one crash image in every loop execution here, to simulate partial
-                    // permutations
-                    let (_, hash) = pool.persist(img.as_slice()).unwrap();
-                    hashes.insert(hash);
+                    applied.push(store.applied());
                 }
             }
+            if let Some(hash) = pool.persist(NVME_BASE_IMAGE, img.as_slice()).unwrap() {
+                hashes.entry(hash).or_insert(applied);
+            }
         }
         hashes
     }
 
-    pub fn write(&mut self, address: usize, data: Vec<u8>) {
-        if (address & NVME_ATOMIC_BLOCK_SIZE_MASK) != 0 || (data.len() & NVME_ATOMIC_BLOCK_SIZE_MASK) != 0 {
+    pub fn write(&mut self, id: usize, address: usize, data: Vec<u8>) {
+        let block_size = self.block_size();
+        if !address.is_multiple_of(block_size) || !data.len().is_multiple_of(block_size) {
             panic!("unaligned NVMe access: addr={} len={}", address, data.len());
         }
-        for offset in (0..data.len()).step_by(NVME_ATOMIC_BLOCK_SIZE) {
+        for offset in (0..data.len()).step_by(block_size) {
             self.unpersisted_content.push(Store {
+                id,
                 address: address + offset,
-                data: data[offset..(offset + NVME_ATOMIC_BLOCK_SIZE)].to_vec(),
+                data: data[offset..(offset + block_size)].to_vec(),
             });
         }
     }
@@ -380,6 +633,84 @@ impl NvmeDevice {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Pmem,
+    Nvme,
+}
+
+/// what `apply_entry` did with a trace entry
+#[derive(Debug, PartialEq, Eq)]
+pub enum Applied {
+    /// nothing that changes the possible crash images
+    Nothing,
+    /// a store or write that is not persisted yet
+    Write(Device),
+    /// a pmem fence or clflush or an NVMe flush, which still has to be completed with
+    /// `complete_barrier`. Crash images are generated before that.
+    Barrier(Device),
+}
+
+/// Apply a trace entry to the device models, up to its barrier. The init checkpoint 255 sets
+/// `had_init`; before it, the test script must not write to the devices.
+pub fn apply_entry(entry: TraceEntry, had_init: &mut bool, pmem: Option<&mut X86PersistentMemory>, nvme: Option<&mut NvmeDevice>) -> Result<Applied> {
+    let applied = match entry {
+        TraceEntry::Pmem { id, event, .. } => match event {
+            PmemEvent::Read { .. } => Applied::Nothing,
+            PmemEvent::Wbinvd => {
+                if *had_init { // yes, there should be no ! here.
+                    bail!("wbinvd should not appear during normal test execution");
+                }
+                Applied::Nothing
+            },
+            PmemEvent::Fence => {
+                pmem.context("pmem event without pmem")?;
+                Applied::Barrier(Device::Pmem)
+            },
+            _ if !*had_init => bail!("pmem event before test script"),
+            PmemEvent::Write { address, size: _, content, non_temporal } => {
+                pmem.context("pmem event without pmem")?.write(id as usize, address as usize, content.as_slice(), non_temporal);
+                Applied::Write(Device::Pmem)
+            },
+            PmemEvent::Clflush { address } => {
+                // approximate Clflush by adding a fence
+                pmem.context("pmem event without pmem")?.clwb(address as usize, None);
+                Applied::Barrier(Device::Pmem)
+            },
+            PmemEvent::Clflushopt { address } | PmemEvent::Clwb { address } => {
+                pmem.context("pmem event without pmem")?.clwb(address as usize, None);
+                Applied::Nothing
+            },
+        },
+        TraceEntry::Nvme { id, event } => match event {
+            NvmeEvent::Read { .. } => Applied::Nothing,
+            _ if !*had_init => bail!("nvme event before test script"),
+            NvmeEvent::Write { offset, length: _, data } => {
+                nvme.context("nvme event without nvme")?.write(id as usize, offset as usize, data);
+                Applied::Write(Device::Nvme)
+            },
+            NvmeEvent::Flush => {
+                nvme.context("nvme event without nvme")?;
+                Applied::Barrier(Device::Nvme)
+            },
+        },
+        TraceEntry::Checkpoint { id: _, value: 255 } => {
+            *had_init = true;
+            Applied::Nothing
+        },
+        TraceEntry::Checkpoint { .. } => Applied::Nothing,
+    };
+    Ok(applied)
+}
+
+/// Complete the barrier of an entry, see `apply_entry`.
+pub fn complete_barrier(device: Device, pmem: Option<&mut X86PersistentMemory>, nvme: Option<&mut NvmeDevice>) {
+    match device {
+        Device::Pmem => pmem.unwrap().fence(),
+        Device::Nvme => nvme.unwrap().flush(),
+    }
+}
+
 fn range_overlap<T>(r1: &Range<T>, r2: &Range<T>) -> Range<T>
 where
     T: std::cmp::Ord + Copy,
@@ -389,3 +720,100 @@ where
         end: min(r1.end, r2.end),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestWorkDir;
+
+    #[test]
+    fn test_model_params() {
+        let vm_config: VmConfig = serde_yaml::from_str(r#"
+            fs_type: pmem
+            pmem_start: 0
+            pmem_len: 4096
+            nvme_len: null
+            qemu_path: ""
+            kernel_path: ""
+            initrd_path: ""
+            qemu_args: []
+            trace_cmd_prefix: ""
+            dump_cmd_prefix: ""
+            recovery_cmd: ""
+            model: { line_granularity: 8, pool_limit: 1024, seed: 7 }
+        "#).unwrap();
+        let mut test_config: TestConfig = serde_yaml::from_str(r#"
+            trace_cmd_suffix: ""
+            checkpoint_range: [0, 1]
+            dump_cmd_suffix: ""
+            model: { pool_limit: 2048 }
+        "#).unwrap();
+        let params = ModelParams::from_configs(&vm_config, &test_config).unwrap();
+        assert_eq!(params, ModelParams { line_granularity: 8, pool_limit: 2048, seed: 7, ..ModelParams::default() });
+
+        test_config.model.line_granularity = Some(16);
+        assert!(ModelParams::from_configs(&vm_config, &test_config).is_err());
+    }
+
+    #[test]
+    fn test_tearing() {
+        let work_dir = TestWorkDir::with_base("tearing", PMEM_BASE_IMAGE, 4096);
+        let (mut pool, mut rng) = work_dir.pool_and_rng();
+
+        let params = ModelParams { tearing_granularity: 1, max_partial_flushes_count: 15, ..ModelParams::default() };
+        let mut pmem = X86PersistentMemory::new(vec![0u8; 4096], &params);
+        // aligned stores stay atomic
+        pmem.write(1, 0, &[1, 2, 3, 4], false);
+        assert_eq!(pmem.unpersisted_store_count(), 1);
+        pmem.persist_unpersisted();
+        // a misaligned store persists byte by byte, in any order
+        pmem.write(2, 70, &[1, 2, 3, 4], false);
+        assert_eq!(pmem.unpersisted_store_count(), 4);
+        let mut applied: Vec<Vec<AppliedStore>> = pmem.generate_random_images(&mut pool, &mut rng).into_values().collect();
+        applied.sort();
+        let piece = |address: usize| AppliedStore { id: 2, address, len: 1 };
+        let subsets: Vec<Vec<AppliedStore>> = (70..74).powerset().skip(1)
+            .map(|addresses| addresses.into_iter().map(piece).collect())
+            .sorted()
+            .collect();
+        assert_eq!(applied, subsets);
+        assert!(applied.contains(&vec![piece(73)]));
+
+        // stores of a line persist in order, only the last one may be torn
+        let mut pmem = X86PersistentMemory::new(vec![0u8; 4096], &params);
+        pmem.write(1, 64, &[1, 1], false);
+        pmem.write(2, 67, &[2, 2], false);
+        let line = &pmem.unpersisted_content[&1];
+        assert_eq!(line.partial_writes(), vec![vec![0], vec![0, 1], vec![0, 2], vec![0, 1, 2]]);
+
+        // by default, it only tears at the 8-byte boundary
+        let mut pmem = X86PersistentMemory::new(vec![0u8; 4096], &ModelParams::default());
+        pmem.write(2, 70, &[1, 2, 3, 4], false);
+        assert_eq!(pmem.unpersisted_store_count(), 2);
+    }
+
+    #[test]
+    fn test_nvme_block_prefixes() {
+        let work_dir = TestWorkDir::with_base("nvme", NVME_BASE_IMAGE, 2048);
+        let (mut pool, mut rng) = work_dir.pool_and_rng();
+
+        let mut nvme = NvmeDevice::new(vec![0u8; 2048], &ModelParams::default());
+        nvme.write(1, 0, vec![1u8; 512]);
+        nvme.write(2, 0, vec![2u8; 512]);
+        nvme.write(3, 1024, vec![3u8; 512]);
+        // block 0: none, 1 or 1+2; block 2: none or 3
+        let images = nvme.generate_random_images(&mut pool, &mut rng);
+        let mut applied: Vec<Vec<usize>> = images.into_values()
+            .map(|applied| applied.iter().map(|store| store.id).collect())
+            .collect();
+        applied.sort();
+        assert_eq!(applied, vec![vec![], vec![1], vec![1, 2], vec![1, 2, 3], vec![1, 3], vec![3]]);
+
+        // too many combinations: sampled, but still prefixes
+        nvme.max_exhaustive_images = 5;
+        for applied in nvme.generate_random_images(&mut pool, &mut rng).into_values() {
+            let ids: Vec<usize> = applied.iter().map(|store| store.id).collect();
+            assert!(!ids.contains(&2) || ids.contains(&1));
+        }
+    }
+}
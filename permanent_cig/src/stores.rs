@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Context, Result};
+use permanent_common::config::{VmConfig, TraceConfig, TraceType};
+use permanent_common::trace::parse_trace_file_bin;
+
+use crate::{crash_metadata, CrashMetadata};
+use crate::image::CrashHash;
+use crate::models::{apply_entry, complete_barrier, Applied, AppliedStore, ModelParams, Store, X86PersistentMemory, NvmeDevice};
+
+/// The unpersisted stores (pmem) or writes (NVMe) that were applied to the nothing-persisted
+/// content to build a crash image. Allows building images with only a subset of them applied.
+pub struct CrashImageStores {
+    /// "pmem" or "nvme"
+    pub device: &'static str,
+    pub metadata: CrashMetadata,
+    base: Vec<u8>,
+    /// in the order of application, one per applied part of a store
+    stores: Vec<Store>,
+}
+
+impl CrashImageStores {
+    /// Reconstruct the stores of a crash image by replaying the analyse trace up to the entry
+    /// where the image was generated. Fails if they do not build the crash image again.
+    pub fn load(work_dir: &String, vm_config: &VmConfig, hash: &CrashHash) -> Result<Self> {
+        let (device, mut records) = crash_metadata(work_dir, hash)?.into_iter().next().unwrap();
+        let metadata = records.remove(0);
+
+        let (pmem, nvme) = replay_until(work_dir, vm_config, metadata.trace_entry_id)?;
+        let (base, unpersisted) = match device {
+            "pmem" => {
+                let pmem = pmem.context("no pmem in the vm config")?;
+                let stores = pmem.unpersisted_content.into_values()
+                    .flat_map(|line| line.all_writes().to_vec())
+                    .collect::<Vec<_>>();
+                (pmem.persisted_content, stores)
+            },
+            _ => {
+                let nvme = nvme.context("no nvme in the vm config")?;
+                (nvme.persisted_content, nvme.unpersisted_content)
+            },
+        };
+
+        // only the applied parts of torn stores, in the recorded order, which matters for
+        // overlapping stores
+        let mut unpersisted: HashMap<AppliedStore, Store> = unpersisted.into_iter()
+            .map(|store| (store.applied(), store))
+            .collect();
+        let mut stores = Vec::new();
+        for applied in metadata.applied.iter() {
+            match unpersisted.remove(applied) {
+                Some(store) => stores.push(store),
+                None => bail!("store {:?} of crash image {} is not unpersisted at id {}", applied, hash, metadata.trace_entry_id),
+            }
+        }
+
+        let stores = Self { device, metadata, base, stores };
+        if &CrashHash::of(stores.build(stores.applied()).as_slice()) != hash {
+            bail!("the replayed stores do not build crash image {}. was the trace replaced?", hash);
+        }
+        Ok(stores)
+    }
+
+    pub fn applied(&self) -> &[AppliedStore] {
+        self.metadata.applied.as_slice()
+    }
+
+    /// Build the crash image with only the given stores applied.
+    pub fn build(&self, applied: &[AppliedStore]) -> Vec<u8> {
+        let applied: HashSet<&AppliedStore> = applied.iter().collect();
+        let mut img = self.base.clone();
+        for store in self.stores.iter().filter(|store| applied.contains(&store.applied())) {
+            img[store.address_range()].copy_from_slice(store.data.as_slice());
+        }
+        img
+    }
+}
+
+/// Replay the analyse trace into the device models up to the entry `end_id`, where the crash
+/// image was generated. A barrier of that entry is not completed, like in
+/// `CrashImageGenerator::replay_trace`.
+fn replay_until(work_dir: &String, vm_config: &VmConfig, end_id: usize) -> Result<(Option<X86PersistentMemory>, Option<NvmeDevice>)> {
+    let (p, n) = vm_config.have_pmem_nvme();
+    let read_base = |name: &str| {
+        let path = format!("{}/{}_base.raw", work_dir, name);
+        std::fs::read(path.as_str()).with_context(|| format!("could not read {}", path))
+    };
+    let params = ModelParams::read(work_dir)?;
+    let mut pmem = if p { Some(X86PersistentMemory::new(read_base("pmem")?, &params)) } else { None };
+    let mut nvme = if n { Some(NvmeDevice::new(read_base("nvme")?, &params)) } else { None };
+
+    let trace_path = TraceConfig::new(work_dir, TraceType::Analyse).trace_path();
+    let file = File::open(trace_path.as_str()).with_context(|| format!("could not open {}", trace_path))?;
+    let mut had_init = false;
+    for entry in parse_trace_file_bin(BufReader::new(file))? {
+        let entry = entry?;
+        let id = entry.id() as usize;
+        if id > end_id {
+            break;
+        }
+        if let Applied::Barrier(device) = apply_entry(entry, &mut had_init, pmem.as_mut(), nvme.as_mut())? {
+            if id == end_id {
+                break;
+            }
+            complete_barrier(device, pmem.as_mut(), nvme.as_mut());
+        }
+    }
+    Ok((pmem, nvme))
+}
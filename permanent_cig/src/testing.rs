@@ -0,0 +1,43 @@
+//! Fixtures shared by the tests of the crate.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::image::ImagePool;
+
+/// A fresh work dir in the temp dir, removed when dropped.
+pub struct TestWorkDir(String);
+
+impl TestWorkDir {
+    /// Create the work dir with a zeroed base image, e.g. `pmem_base.raw`.
+    pub fn with_base(name: &str, base_name: &str, len: usize) -> Self {
+        // tests run in parallel and may use the same name
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let run = RUNS.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("permanent_cig_test_{}_{}_{}", name, std::process::id(), run))
+            .to_str().unwrap().to_string();
+        crate::remove_dir(&path).unwrap();
+        std::fs::create_dir_all(path.as_str()).unwrap();
+        std::fs::write(format!("{}/{}", path, base_name), vec![0u8; len]).unwrap();
+        Self(path)
+    }
+
+    /// An image pool of the work dir and a seeded generator, to call the device models directly.
+    pub fn pool_and_rng(&self) -> (ImagePool, fastrand::Rng) {
+        (ImagePool::new(&self.0).unwrap(), fastrand::Rng::with_seed(0))
+    }
+}
+
+impl Deref for TestWorkDir {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl Drop for TestWorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.0.as_str());
+    }
+}
@@ -29,9 +29,10 @@ impl Action for ActionChain {
 
         let mut profiler = Profiler::new();
 
+        let mut aborted = false;
         for action in &self.actions {
             match action.run() {
-                None => break,
+                None => { aborted = true; break },
                 Some(res) => profiler.register_vec(res),
             }
         }
@@ -42,6 +43,10 @@ impl Action for ActionChain {
             profiler.to_file(&profile_file[..]).expect("Could not write profiling data");
         }
 
+        // a chain that did not run to completion fails like a single action
+        if aborted {
+            return None;
+        }
         return Some(profiles);
     }
 }
@@ -0,0 +1,41 @@
+use clap::{Parser, Subcommand};
+
+use permanent_common::trace::{import_trace_text, export_trace_text};
+
+fn main() {
+    let args = Args::parse();
+    match args.command {
+        Command::Import { text, trace, index } => {
+            import_trace_text(text.as_str(), trace.as_str(), index).expect("could not import trace");
+        },
+        Command::Export { trace, text } => {
+            if !export_trace_text(trace.as_str(), text.as_str()).expect("could not export trace") {
+                eprintln!("WARNING: trace is truncated. exported the entries up to the cut.");
+            }
+        },
+    }
+}
+
+/// Convert traces between the binary format and the text format (JSON Lines, header first).
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// text trace to binary trace
+    Import {
+        text: String,
+        trace: String,
+        /// also write an index for random access
+        #[clap(short, long, action)]
+        index: bool,
+    },
+    /// binary trace to text trace
+    Export {
+        trace: String,
+        text: String,
+    },
+}
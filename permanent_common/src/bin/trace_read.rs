@@ -1,37 +1,106 @@
-use std::env;
-use std::io::BufReader;
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
 use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
 
-use permanent_common::trace::parse_trace_file_bin;
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+
+use permanent_common::config::VmConfig;
+use permanent_common::symbolize::Symbolizer;
+use permanent_common::trace::{parse_trace_file_bin, IndexedTrace};
 use permanent_common::trace::{PmemEvent, NvmeEvent, TraceEntry};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 || args.len() > 3 {
-        println!("usage: {} <FILE> [--nodata]", args[0]);
-        return;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Device {
+    Pmem,
+    Nvme,
+    Checkpoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Read,
+    Write,
+    Fence,
+    Clflush,
+    Clflushopt,
+    Clwb,
+    Wbinvd,
+    Flush,
+    Checkpoint,
+}
+
+fn device_of(entry: &TraceEntry) -> Device {
+    match entry {
+        TraceEntry::Pmem { .. } => Device::Pmem,
+        TraceEntry::Nvme { .. } => Device::Nvme,
+        TraceEntry::Checkpoint { .. } => Device::Checkpoint,
     }
-    
-    let file = File::open(args[1].as_str()).unwrap();
-    let nodata = args.len() == 3 && args[2] == "--nodata";
-    for item in parse_trace_file_bin(BufReader::new(file)) {
-        let mut item = item.unwrap();
-        if nodata {
-            remove_data(&mut item);
-        }
-        println!("{:?}", item);
+}
+
+fn kind_of(entry: &TraceEntry) -> Kind {
+    match entry {
+        TraceEntry::Pmem { event, .. } => match event {
+            PmemEvent::Read { .. } => Kind::Read,
+            PmemEvent::Write { .. } => Kind::Write,
+            PmemEvent::Fence => Kind::Fence,
+            PmemEvent::Clflush { .. } => Kind::Clflush,
+            PmemEvent::Clflushopt { .. } => Kind::Clflushopt,
+            PmemEvent::Clwb { .. } => Kind::Clwb,
+            PmemEvent::Wbinvd => Kind::Wbinvd,
+        },
+        TraceEntry::Nvme { id: _, event } => match event {
+            NvmeEvent::Read { .. } => Kind::Read,
+            NvmeEvent::Write { .. } => Kind::Write,
+            NvmeEvent::Flush => Kind::Flush,
+        },
+        TraceEntry::Checkpoint { .. } => Kind::Checkpoint,
+    }
+}
+
+/// address range touched by the entry. Cache line flushes cover a whole line.
+fn address_range(entry: &TraceEntry) -> Option<Range<u64>> {
+    match entry {
+        TraceEntry::Pmem { event, .. } => match event {
+            PmemEvent::Read { address, size, .. } | PmemEvent::Write { address, size, .. } => Some(*address..(address + size)),
+            PmemEvent::Clflush { address } | PmemEvent::Clflushopt { address } | PmemEvent::Clwb { address } => {
+                let line = address & !63;
+                Some(line..(line + 64))
+            },
+            PmemEvent::Fence | PmemEvent::Wbinvd => None,
+        },
+        TraceEntry::Nvme { id: _, event } => match event {
+            NvmeEvent::Read { offset, length } | NvmeEvent::Write { offset, length, .. } => Some(*offset..(offset + length)),
+            NvmeEvent::Flush => None,
+        },
+        TraceEntry::Checkpoint { .. } => None,
+    }
+}
+
+/// address and content of the payload, if any
+fn payload(entry: &TraceEntry) -> Option<(u64, &[u8])> {
+    match entry {
+        TraceEntry::Pmem { event: PmemEvent::Read { address, size: _, content }, .. }
+        | TraceEntry::Pmem { event: PmemEvent::Write { address, size: _, content, non_temporal: _ }, .. } => {
+            Some((*address, content.as_slice()))
+        },
+        TraceEntry::Nvme { id: _, event: NvmeEvent::Write { offset, length: _, data } } => Some((*offset, data.as_slice())),
+        _ => None,
     }
 }
 
 fn remove_data(item: &mut TraceEntry) {
     match item {
-        //TraceEntry::Pmem { id: _, event } => {
-        //    match event {
-        //        PmemEvent::Read { address: _, size: _, content } => { content.clear(); },
-        //        PmemEvent::Write { address: _, size: _, content, non_temporal: _ } => { content.clear(); },
-        //        _ => { },
-        //    }
-        //},
+        TraceEntry::Pmem { event, .. } => {
+            match event {
+                PmemEvent::Read { address: _, size: _, content } => { content.clear(); },
+                PmemEvent::Write { address: _, size: _, content, non_temporal: _ } => { content.clear(); },
+                _ => { },
+            }
+        },
         TraceEntry::Nvme { id: _, event } => {
             match event {
                 NvmeEvent::Write { offset: _, length: _, data } => { data.clear(); },
@@ -41,3 +110,283 @@ fn remove_data(item: &mut TraceEntry) {
         _ => { },
     }
 }
+
+/// Parse `START..END` (END exclusive). Both bounds can be given in hex with a `0x` prefix.
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let parse = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }.map_err(|e| format!("invalid number {}: {}", s, e));
+    let (start, end) = s.split_once("..").ok_or("expected START..END")?;
+    Ok(parse(start)?..parse(end)?)
+}
+
+fn write_hexdump<W: Write>(dst: &mut W, address: u64, data: &[u8]) -> std::io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(dst, "  {:016x} ", address + (i * 16) as u64)?;
+        for byte in line {
+            write!(dst, " {:02x}", byte)?;
+        }
+        let ascii: String = line.iter()
+            .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+            .collect();
+        writeln!(dst, "{:width$}  |{}|", "", ascii, width = (16 - line.len()) * 3)?;
+    }
+    Ok(())
+}
+
+/// counts of a checkpoint interval
+#[derive(Default, Serialize)]
+struct IntervalStats {
+    /// value of the checkpoint the interval starts with, none before the first checkpoint
+    checkpoint: Option<u8>,
+    flushes: usize,
+    fences: usize,
+    bytes_written: u64,
+}
+
+#[derive(Default, Serialize)]
+struct Stats {
+    entries: usize,
+    pmem: BTreeMap<Kind, usize>,
+    nvme: BTreeMap<Kind, usize>,
+    checkpoints: usize,
+    pmem_bytes_written: u64,
+    nvme_bytes_written: u64,
+    intervals: Vec<IntervalStats>,
+}
+
+impl Stats {
+    fn start_interval(&mut self, checkpoint: Option<u8>) {
+        self.intervals.push(IntervalStats { checkpoint, ..Default::default() });
+    }
+
+    fn add(&mut self, entry: &TraceEntry) {
+        self.entries += 1;
+        let kind = kind_of(entry);
+        if self.intervals.is_empty() {
+            self.start_interval(None);
+        }
+        let interval = self.intervals.last_mut().unwrap();
+        let written = match kind {
+            Kind::Write => payload(entry).map_or(0, |(_, data)| data.len() as u64),
+            _ => 0,
+        };
+        match kind {
+            Kind::Clflush | Kind::Clflushopt | Kind::Clwb | Kind::Flush | Kind::Wbinvd => interval.flushes += 1,
+            Kind::Fence => interval.fences += 1,
+            _ => { },
+        }
+        interval.bytes_written += written;
+        match device_of(entry) {
+            Device::Pmem => {
+                *self.pmem.entry(kind).or_default() += 1;
+                self.pmem_bytes_written += written;
+            },
+            Device::Nvme => {
+                *self.nvme.entry(kind).or_default() += 1;
+                self.nvme_bytes_written += written;
+            },
+            Device::Checkpoint => self.checkpoints += 1,
+        }
+    }
+
+    fn print(&self) {
+        println!("entries: {}", self.entries);
+        println!("checkpoints: {}", self.checkpoints);
+        for (device, counts, written) in [("pmem", &self.pmem, self.pmem_bytes_written), ("nvme", &self.nvme, self.nvme_bytes_written)] {
+            if counts.is_empty() {
+                continue;
+            }
+            println!("{}: {} bytes written", device, written);
+            for (kind, count) in counts {
+                println!("  {:?}: {}", kind, count);
+            }
+        }
+        println!("per checkpoint interval:");
+        for interval in &self.intervals {
+            let start = interval.checkpoint.map_or("start".to_string(), |value| format!("checkpoint {}", value));
+            println!("  {}: {} flushes, {} fences, {} bytes written", start, interval.flushes, interval.fences, interval.bytes_written);
+        }
+    }
+}
+
+/// The symbols given on the command line, otherwise those of the kernel in the vm config of the
+/// work dir the trace belongs to (`{work_dir}/analyse/trace.bin`).
+fn load_symbolizer(args: &Args) -> Option<Symbolizer> {
+    if let Some(path) = &args.symbols {
+        return Some(Symbolizer::open(path.as_str()).expect("could not load symbols"));
+    }
+    let work_dir = Path::new(args.file.as_str()).parent()?.parent()?;
+    let vm_config: VmConfig = serde_yaml::from_reader(File::open(work_dir.join("vm_config.yaml")).ok()?).ok()?;
+    Symbolizer::for_kernel(vm_config.kernel_path.as_str()).ok()
+}
+
+/// e.g. "store at zil_pmem_prb_write+0x4a"
+fn describe_origin(entry: &TraceEntry, symbolizer: &Symbolizer) -> Option<String> {
+    let TraceEntry::Pmem { event, rip, .. } = entry else { return None };
+    let location = symbolizer.symbolize(*rip)?;
+    let what = match event {
+        PmemEvent::Read { .. } => "load".to_string(),
+        PmemEvent::Write { .. } => "store".to_string(),
+        _ => kind_of(entry).to_possible_value().unwrap().get_name().to_string(),
+    };
+    Some(format!("{} at {}", what, location))
+}
+
+fn print_entry<W: Write>(out: &mut W, mut entry: TraceEntry, args: &Args, symbolizer: Option<&Symbolizer>) -> std::io::Result<()> {
+    if args.json {
+        if args.nodata {
+            remove_data(&mut entry);
+        }
+        serde_json::to_writer(&mut *out, &entry)?;
+        return writeln!(out);
+    }
+    let hexdump = args.hexdump.then(|| payload(&entry).map(|(address, data)| (address, data.to_vec()))).flatten();
+    if args.nodata || args.hexdump {
+        remove_data(&mut entry);
+    }
+    writeln!(out, "{:?}", entry)?;
+    if let Some(origin) = symbolizer.and_then(|symbolizer| describe_origin(&entry, symbolizer)) {
+        writeln!(out, "  {}", origin)?;
+    }
+    if let Some((address, data)) = hexdump {
+        write_hexdump(out, address, data.as_slice())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let matches = |entry: &TraceEntry| {
+        (args.device.is_empty() || args.device.contains(&device_of(entry)))
+            && (args.kind.is_empty() || args.kind.contains(&kind_of(entry)))
+            && args.ids.as_ref().is_none_or(|ids| ids.contains(&entry.id()))
+            && args.addresses.as_ref().is_none_or(|addresses| {
+                address_range(entry).is_some_and(|range| range.start < addresses.end && addresses.start < range.end)
+            })
+    };
+
+    let mut truncated_check = None;
+    let entries: Box<dyn Iterator<Item = anyhow::Result<TraceEntry>> + '_>;
+    match args.checkpoints.as_deref() {
+        Some(&[start, end]) => match IndexedTrace::open(args.file.as_str()) {
+            // jump to the start checkpoint using the index
+            Ok(trace) => {
+                if !args.stats && !args.json {
+                    println!("{:?}", trace.header);
+                }
+                entries = Box::new(trace.entries_between_checkpoints(start, end).expect("could not seek in trace"));
+            },
+            Err(e) => {
+                eprintln!("WARNING: {:#}. reading the trace from the start.", e);
+                let file = File::open(args.file.as_str()).expect("could not open trace file");
+                let trace = parse_trace_file_bin(BufReader::new(file)).expect("invalid trace file");
+                if !args.stats && !args.json {
+                    println!("{:?}", trace.header);
+                }
+                let is_checkpoint = |entry: &anyhow::Result<TraceEntry>, checkpoint: u8| {
+                    matches!(entry, Ok(TraceEntry::Checkpoint { id: _, value }) if *value == checkpoint)
+                };
+                // from the first checkpoint START up to and including the following checkpoint END
+                let mut done = false;
+                entries = Box::new(trace
+                    .skip_while(move |entry| entry.is_ok() && !is_checkpoint(entry, start))
+                    .take_while(move |entry| {
+                        let take = !done;
+                        done = done || is_checkpoint(entry, end) || entry.is_err();
+                        take
+                    }));
+            },
+        },
+        _ => {
+            let file = File::open(args.file.as_str()).expect("could not open trace file");
+            let trace = parse_trace_file_bin(BufReader::new(file)).expect("invalid trace file");
+            if !args.stats && !args.json {
+                println!("{:?}", trace.header);
+            }
+            let trace = truncated_check.insert(trace);
+            entries = Box::new(trace.by_ref());
+        },
+    }
+
+    let symbolizer = (!args.stats && !args.json).then(|| load_symbolizer(&args)).flatten();
+    let mut stats = Stats::default();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for entry in entries {
+        let entry = entry.expect("could not parse trace");
+        if let TraceEntry::Checkpoint { id: _, value } = entry {
+            stats.start_interval(Some(value));
+        }
+        if !matches(&entry) {
+            continue;
+        }
+        if args.stats {
+            stats.add(&entry);
+            continue;
+        }
+        match print_entry(&mut out, entry, &args, symbolizer.as_ref()) {
+            Ok(()) => { },
+            // e.g. piped into head
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return,
+            Err(e) => panic!("could not write output: {}", e),
+        }
+    }
+    if let Err(e) = out.flush() {
+        if e.kind() == ErrorKind::BrokenPipe {
+            return;
+        }
+        panic!("could not write output: {}", e);
+    }
+    drop(out);
+
+    if args.stats {
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&stats).expect("could not serialize stats"));
+        } else {
+            stats.print();
+        }
+    }
+    if truncated_check.is_some_and(|trace| !trace.is_terminated()) {
+        eprintln!("WARNING: trace is truncated");
+    }
+}
+
+/// Print the entries of a binary trace file.
+#[derive(Debug, Parser)]
+struct Args {
+    file: String,
+    /// only entries of these devices
+    #[clap(short, long, value_enum)]
+    device: Vec<Device>,
+    /// only events of these kinds
+    #[clap(short, long, value_enum)]
+    kind: Vec<Kind>,
+    /// only entries with ids in START..END
+    #[clap(long, value_parser = parse_range)]
+    ids: Option<Range<u64>>,
+    /// only entries touching addresses (pmem) or offsets (nvme) in START..END
+    #[clap(long, value_parser = parse_range)]
+    addresses: Option<Range<u64>>,
+    /// only entries from checkpoint START to checkpoint END. Seeks using the trace index if there
+    /// is one.
+    #[clap(long, num_args = 2, value_names = ["START", "END"])]
+    checkpoints: Option<Vec<u8>>,
+    /// leave out written and read data
+    #[clap(long, action)]
+    nodata: bool,
+    /// print written and read data as hexdump
+    #[clap(long, action, conflicts_with = "nodata")]
+    hexdump: bool,
+    /// System.map or vmlinux to resolve instruction addresses of pmem events. By default, the kernel
+    /// build of the vm config is used if the trace is in a work dir.
+    #[clap(long)]
+    symbols: Option<String>,
+    /// print entries as JSON Lines
+    #[clap(long, action)]
+    json: bool,
+    /// print counts per event type, bytes written and flushes/fences per checkpoint interval
+    #[clap(long, action)]
+    stats: bool,
+}
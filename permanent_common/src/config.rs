@@ -1,5 +1,11 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+
+use anyhow::{Context, Result};
 use enumset::{EnumSet, EnumSetType};
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
+
+use crate::hash::ConfigHash;
 
 #[derive(Debug, EnumSetType)]
 pub enum TraceOption {
@@ -56,6 +62,11 @@ pub struct TcgPluginConfig {
     pub pmem_base_image_path: Option<String>,
     pub trace_what: EnumSet<TraceOption>,
     pub out_trace_file: String,
+    /// write an index next to the trace for random access
+    pub trace_index: bool,
+    /// hashes of the config files of the work dir, recorded in the trace header
+    pub vm_config_hash: Option<ConfigHash>,
+    pub test_config_hash: Option<ConfigHash>,
 }
 
 impl TcgPluginConfig {
@@ -84,6 +95,15 @@ impl TcgPluginConfig {
         if let Some(trace_what_string) = maybe_trace_what_string {
             s.push_str(format!(",trace_what={}", trace_what_string).as_str());
         }
+        if self.trace_index {
+            s.push_str(",trace_index=on");
+        }
+        if let Some(hash) = &self.vm_config_hash {
+            s.push_str(format!(",vm_config_hash={}", hash).as_str());
+        }
+        if let Some(hash) = &self.test_config_hash {
+            s.push_str(format!(",test_config_hash={}", hash).as_str());
+        }
         s.push_str(format!(",out_trace_file={}", self.out_trace_file).as_str());
         s
     }
@@ -95,6 +115,7 @@ pub struct VmConfig {
     pub fs_type: String,
     pub pmem_start: Option<u64>, // only used for pmem/hybrid; yaml files can simply leave it out
     pub pmem_len: Option<u64>,
+    pub nvme_len: Option<u64>, // only used to create base images for nvme/hybrid
     pub qemu_path: String,
     pub kernel_path: String,
     pub initrd_path: String,
@@ -102,9 +123,20 @@ pub struct VmConfig {
     pub trace_cmd_prefix: String,
     pub dump_cmd_prefix: String,
     pub recovery_cmd: String,
+    /// persistency model parameters for all tests on this VM
+    #[serde(default)]
+    pub model: ModelConfig,
 }
 
 impl VmConfig {
+    /// Hash of the fields that change what a trace captures. Changes of the `model` section,
+    /// comments or formatting keep it.
+    pub fn capture_hash(&self) -> ConfigHash {
+        let fields = (&self.fs_type, self.pmem_start, self.pmem_len, self.nvme_len,
+            &self.trace_cmd_prefix, &self.dump_cmd_prefix, &self.recovery_cmd);
+        ConfigHash::of(serde_json::to_vec(&fields).unwrap().as_slice())
+    }
+
     pub fn have_pmem_nvme(&self) -> (bool, bool) {
         match self.fs_type.as_str() {
             "pmem" => (true, false),
@@ -121,30 +153,138 @@ pub struct TestConfig {
     pub trace_cmd_suffix: String,
     pub checkpoint_range: (u8, u8),
     pub dump_cmd_suffix: String,
+    /// properties checked by the report. Without it, the report checks nothing.
+    pub expect: Option<Expect>,
+    /// persistency model parameters, override those of the vm config
+    #[serde(default)]
+    pub model: ModelConfig,
+}
+
+impl TestConfig {
+    /// Hash of the fields that change what a trace captures, see [VmConfig::capture_hash].
+    pub fn capture_hash(&self) -> ConfigHash {
+        let fields = (&self.trace_cmd_suffix, self.checkpoint_range, &self.dump_cmd_suffix);
+        ConfigHash::of(serde_json::to_vec(&fields).unwrap().as_slice())
+    }
+}
+
+/// Capture hashes of vm_config.yaml and test_config.yaml of the work dir, `None` for missing files.
+pub fn capture_hashes(work_dir: &str) -> Result<(Option<ConfigHash>, Option<ConfigHash>)> {
+    let read = |name: &str| -> Result<Option<File>> {
+        let path = format!("{}/{}", work_dir, name);
+        match File::open(path.as_str()) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("could not read {}", path)),
+        }
+    };
+    let vm = read("vm_config.yaml")?.map(|file| serde_yaml::from_reader::<_, VmConfig>(BufReader::new(file)))
+        .transpose().context("invalid vm_config.yaml")?;
+    let test = read("test_config.yaml")?.map(|file| serde_yaml::from_reader::<_, TestConfig>(BufReader::new(file)))
+        .transpose().context("invalid test_config.yaml")?;
+    Ok((vm.map(|c| c.capture_hash()), test.map(|c| c.capture_hash())))
+}
+
+/// `model` section of vm and test configs. Unset parameters keep the defaults of permanent_cig.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// granularity of pmem stores that persist together, 8 or 64
+    pub line_granularity: Option<usize>,
+    /// maximum number of subsets of unpersisted lines per crash point
+    pub max_unpersisted_subsets: Option<usize>,
+    /// maximum number of partial flush combinations of a line subset
+    pub max_partial_flushes_count: Option<usize>,
+    /// persistence domain of pmem stores, `adr` or `eadr`
+    pub pmem_platform: Option<PmemPlatform>,
+    /// eADR: maximum number of store prefixes per crash point
+    pub max_eadr_prefixes: Option<usize>,
+    /// misaligned pmem stores may persist partially in pieces of this size: 1, 2, 4 or 8
+    pub tearing_granularity: Option<usize>,
+    /// enumerate all NVMe block states if there are at most this many
+    pub nvme_exhaustive_images_max_amount: Option<usize>,
+    /// number of sampled NVMe crash images otherwise
+    pub nvme_random_images_amount: Option<usize>,
+    /// log2 of the size of atomically written NVMe blocks
+    pub nvme_atomic_block_size_shift: Option<usize>,
+    /// maximum size of the crash image deltas in bytes
+    pub pool_limit: Option<usize>,
+    /// seed of the random choice of crash images. Random if unset.
+    pub seed: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PmemPlatform {
+    /// stores are durable after they are flushed from the cache and fenced
+    #[default]
+    Adr,
+    /// the caches are in the persistence domain, stores are durable in store order
+    Eadr,
+}
+
+impl ModelConfig {
+    /// Parameters of `self`, with unset ones taken from `fallback`.
+    pub fn or(&self, fallback: &ModelConfig) -> ModelConfig {
+        ModelConfig {
+            line_granularity: self.line_granularity.or(fallback.line_granularity),
+            max_unpersisted_subsets: self.max_unpersisted_subsets.or(fallback.max_unpersisted_subsets),
+            max_partial_flushes_count: self.max_partial_flushes_count.or(fallback.max_partial_flushes_count),
+            pmem_platform: self.pmem_platform.or(fallback.pmem_platform),
+            max_eadr_prefixes: self.max_eadr_prefixes.or(fallback.max_eadr_prefixes),
+            tearing_granularity: self.tearing_granularity.or(fallback.tearing_granularity),
+            nvme_exhaustive_images_max_amount: self.nvme_exhaustive_images_max_amount.or(fallback.nvme_exhaustive_images_max_amount),
+            nvme_random_images_amount: self.nvme_random_images_amount.or(fallback.nvme_random_images_amount),
+            nvme_atomic_block_size_shift: self.nvme_atomic_block_size_shift.or(fallback.nvme_atomic_block_size_shift),
+            pool_limit: self.pool_limit.or(fallback.pool_limit),
+            seed: self.seed.or(fallback.seed),
+        }
+    }
+}
+
+/// expected properties of a test
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// logical operations between two consecutive checkpoints that must be atomic, e.g. `[[1, 2]]`
+    #[serde(default)]
+    pub atomic: Vec<(u8, u8)>,
+    /// checkpoints that must have a single final state
+    #[serde(default)]
+    pub sfs: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub enum TraceType {
     /// execute test case. Trace all writes/fences/flushes/checkpoints
     Analyse,
-    /// do recovery trace. Trace all reads/checkpoints
-    PostSuccess,
+    /// do recovery trace. Trace all reads/checkpoints.
+    /// Without hashes, the final image with everything persisted is used.
+    PostSuccess { pmem_hash: Option<String>, nvme_hash: Option<String> },
     /// dump file system and verify integrity. Trace all checkpoints
     PostFailure { pmem_hash: Option<String>, nvme_hash: Option<String> },
+    /// boot until the shell prompt and save the vm state for post failure runs. Trace nothing
+    Snapshot,
 }
 
 /// configuration for a single tracing operation
 #[derive(Clone)]
 pub struct TraceConfig {
     pub trace_type: TraceType,
+    work_dir: String,
     dir: String,
+    /// full images to boot in a post failure trace instead of the crash images with its hashes,
+    /// e.g. images that are not in the crash images directory
+    pub pmem_image_source: Option<String>,
+    pub nvme_image_source: Option<String>,
 }
 
 impl TraceConfig {
     pub fn new(work_dir: &String, trace_type: TraceType) -> Self {
         let prefix = match &trace_type {
             TraceType::Analyse => "analyse".to_string(),
-            TraceType::PostSuccess => "post_success".to_string(),
+            TraceType::PostSuccess { .. } => "post_success".to_string(),
+            TraceType::Snapshot => "snapshot".to_string(),
             TraceType::PostFailure { pmem_hash, nvme_hash } => {
                 let mut s = "post".to_string();
                 if let Some(hash) = pmem_hash {
@@ -160,10 +300,24 @@ impl TraceConfig {
         };
         Self {
             trace_type,
+            work_dir: work_dir.clone(),
             dir: format!("{}/{}", work_dir, prefix),
+            pmem_image_source: None,
+            nvme_image_source: None,
         }
     }
 
+    pub fn work_dir(&self) -> String {
+        self.work_dir.clone()
+    }
+
+    /// Create the trace dir in `parent` instead of the work dir, e.g. for traces that are not part
+    /// of a stage.
+    pub fn set_parent_dir(&mut self, parent: &str) {
+        let name = self.dir.rsplit('/').next().unwrap().to_string();
+        self.dir = format!("{}/{}", parent, name);
+    }
+
     pub fn trace_dir(&self) -> String {
         self.dir.clone()
     }
@@ -191,4 +345,12 @@ impl TraceConfig {
     pub fn io_log_path(&self) -> String {
         format!("{}/io_log", self.dir)
     }
+
+    pub fn monitor_path(&self) -> String {
+        format!("{}/monitor.sock", self.dir)
+    }
+
+    pub fn snapshot_path(&self) -> String {
+        format!("{}/vm.state", self.dir)
+    }
 }
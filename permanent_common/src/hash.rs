@@ -0,0 +1,73 @@
+//! Hashes of crash images and semantic states as they appear in the index files, and of
+//! config files as recorded in trace headers.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+macro_rules! hex_hash {
+    ($name:ident) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub struct $name(pub blake3::Hash);
+
+        impl $name {
+            pub fn of(data: &[u8]) -> Self {
+                Self(blake3::hash(data))
+            }
+        }
+
+        // ordered by hex representation, so that sorted output is deterministic
+        impl Ord for $name {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.0.as_bytes().cmp(other.0.as_bytes())
+            }
+        }
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0.to_hex())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = blake3::HexError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                blake3::Hash::from_hex(s).map(Self)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer
+            {
+                serializer.serialize_str(&self.0.to_hex())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>
+            {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+hex_hash!(CrashHash);
+hex_hash!(StateHash);
+hex_hash!(ConfigHash);
+
+/// state dump recorded for crash images whose recovery or dump failed
+pub const FAILED_STATE_DUMP: &[u8] = b"FAILED";
@@ -0,0 +1,125 @@
+//! Crash images stored as differences to a base image of the work dir. The crash images
+//! directory contains `{hash}.delta` files written by the crash image generator, or full
+//! `{hash}.raw` files. Full images are only materialized when a VM boots them.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::hash::CrashHash;
+
+const DELTA_MAGIC: &[u8; 8] = b"PERMDLTA";
+
+/// granularity of the comparison with the base, a cache line
+const CHUNK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageDelta {
+    /// file name of the base image in the work dir, e.g. `pmem_base.raw`
+    pub base: String,
+    pub len: u64,
+    /// (offset, content) of the ranges that differ from the base, sorted and not adjacent
+    pub runs: Vec<(u64, Vec<u8>)>,
+}
+
+impl ImageDelta {
+    pub fn diff(base_name: &str, base: &[u8], image: &[u8]) -> Self {
+        assert_eq!(base.len(), image.len(), "crash image and base differ in size");
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (i, (base_chunk, chunk)) in base.chunks(CHUNK_SIZE).zip(image.chunks(CHUNK_SIZE)).enumerate() {
+            if base_chunk == chunk {
+                continue;
+            }
+            let offset = (i * CHUNK_SIZE) as u64;
+            match runs.last_mut() {
+                Some((start, content)) if *start + content.len() as u64 == offset => content.extend_from_slice(chunk),
+                _ => runs.push((offset, chunk.to_vec())),
+            }
+        }
+        Self { base: base_name.to_string(), len: image.len() as u64, runs }
+    }
+
+    /// number of bytes that differ from the base, rounded up to chunks
+    pub fn size(&self) -> usize {
+        self.runs.iter().map(|(_, content)| content.len()).sum()
+    }
+
+    pub fn apply(&self, base: &[u8]) -> Result<Vec<u8>> {
+        if base.len() as u64 != self.len {
+            bail!("base image {} has {} bytes, expected {}", self.base, base.len(), self.len);
+        }
+        let mut image = base.to_vec();
+        for (offset, content) in self.runs.iter() {
+            let offset = *offset as usize;
+            image.get_mut(offset..offset + content.len()).context("delta exceeds the image")?
+                .copy_from_slice(content.as_slice());
+        }
+        Ok(image)
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("could not create {}", path))?;
+        let mut writer = snap::write::FrameEncoder::new(BufWriter::new(file));
+        writer.write_all(DELTA_MAGIC)?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.into_inner().map_err(|err| err.into_error())?.flush()?;
+        Ok(())
+    }
+
+    pub fn read(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("could not open {}", path))?;
+        let mut reader = snap::read::FrameDecoder::new(BufReader::new(file));
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).with_context(|| format!("could not read {}", path))?;
+        if &magic != DELTA_MAGIC {
+            bail!("{} is not a crash image delta", path);
+        }
+        bincode::deserialize_from(&mut reader).with_context(|| format!("could not parse {}", path))
+    }
+}
+
+/// Write the full crash image with the given hash to `dst`, from a delta or a full image in the
+/// crash images directory.
+pub fn materialize_crash_image(work_dir: &String, hash: &str, dst: &str) -> Result<()> {
+    let raw_path = format!("{}/crash_images/{}.raw", work_dir, hash);
+    if Path::new(raw_path.as_str()).exists() {
+        std::fs::copy(raw_path.as_str(), dst).with_context(|| format!("could not copy {}", raw_path))?;
+        return Ok(());
+    }
+    let delta = ImageDelta::read(format!("{}/crash_images/{}.delta", work_dir, hash).as_str())?;
+    let base_path = format!("{}/{}", work_dir, delta.base);
+    let base = std::fs::read(base_path.as_str()).with_context(|| format!("could not read {}", base_path))?;
+    let image = delta.apply(base.as_slice())?;
+    // the base images must not change after crash image generation
+    if CrashHash::of(image.as_slice()).to_string() != hash {
+        bail!("crash image {} does not match its hash. was {} modified?", hash, delta.base);
+    }
+    std::fs::write(dst, image.as_slice()).with_context(|| format!("could not write {}", dst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let base = vec![0u8; 4096];
+        let mut image = base.clone();
+        image[10] = 1;
+        image[64] = 2; // adjacent chunk, merged into the first run
+        image[1000..1100].fill(3);
+        let delta = ImageDelta::diff("pmem_base.raw", base.as_slice(), image.as_slice());
+        assert_eq!(delta.runs.iter().map(|(offset, content)| (*offset, content.len())).collect::<Vec<_>>(),
+            vec![(0, 128), (960, 192)]);
+        assert_eq!(delta.apply(base.as_slice()).unwrap(), image);
+        assert!(delta.apply(&base[..1024]).is_err());
+
+        let path = std::env::temp_dir().join(format!("permanent_delta_test_{}", std::process::id()));
+        delta.write(path.to_str().unwrap()).unwrap();
+        assert_eq!(ImageDelta::read(path.to_str().unwrap()).unwrap(), delta);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 pub mod profiler;
 pub mod action;
 pub mod config;
+pub mod hash;
+pub mod image;
 pub mod trace;
+pub mod symbolize;
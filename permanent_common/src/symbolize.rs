@@ -0,0 +1,150 @@
+//! Resolve guest instruction addresses to kernel functions, using `System.map` or an ELF vmlinux.
+//! The addresses only match the symbols if the guest kernel runs with `nokaslr`.
+
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+
+use addr2line::gimli;
+use addr2line::object::{self, Object, ObjectSymbol, SymbolKind};
+use anyhow::{bail, Context, Result};
+
+type DwarfContext = addr2line::Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>;
+
+struct Symbol {
+    address: u64,
+    /// unknown for System.map, then the symbol ends at the next one. The last one has no known end.
+    size: Option<u64>,
+    name: String,
+}
+
+/// A symbolized instruction address, e.g. `zil_pmem_prb_write+0x4a (module/zfs/zil_pmem.c:123)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub function: String,
+    pub offset: u64,
+    /// from DWARF, only available for vmlinux built with debug info
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.function, self.offset)?;
+        if let Some(file) = &self.file {
+            write!(f, " ({}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Symbolizer {
+    /// sorted by address
+    symbols: Vec<Symbol>,
+    dwarf: Option<DwarfContext>,
+}
+
+impl Symbolizer {
+    /// Load symbols from a `System.map` or an ELF file, depending on the content.
+    pub fn open(path: &str) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("could not read {}", path))?;
+        if data.starts_with(b"\x7fELF") {
+            Self::from_elf(&data).with_context(|| format!("could not load symbols from {}", path))
+        } else {
+            Self::from_system_map(data.as_slice()).with_context(|| format!("could not parse {}", path))
+        }
+    }
+
+    /// Find `vmlinux` or `System.map` of the kernel build that `kernel_path` (usually
+    /// `arch/x86/boot/bzImage`) belongs to. vmlinux is preferred, as it may contain debug info.
+    pub fn for_kernel(kernel_path: &str) -> Result<Self> {
+        for dir in Path::new(kernel_path).ancestors().skip(1) {
+            for name in ["vmlinux", "System.map"] {
+                let path = dir.join(name);
+                if path.is_file() {
+                    return Self::open(path.to_str().context("invalid path")?);
+                }
+            }
+        }
+        bail!("no vmlinux or System.map found for kernel {}", kernel_path)
+    }
+
+    /// Parse lines like `ffffffff81000000 T _stext`. Only text symbols are used.
+    pub fn from_system_map<R: BufRead>(src: R) -> Result<Self> {
+        let mut symbols = Vec::new();
+        for line in src.lines() {
+            let line = line?;
+            let mut parts = line.split_whitespace();
+            let (Some(address), Some(kind), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+                continue;
+            };
+            if !matches!(kind, "t" | "T" | "w" | "W") {
+                continue;
+            }
+            let address = u64::from_str_radix(address, 16).with_context(|| format!("invalid address in line: {}", line))?;
+            symbols.push(Symbol { address, size: None, name: name.to_string() });
+        }
+        Ok(Self::with_symbols(symbols, None))
+    }
+
+    pub fn from_elf(data: &[u8]) -> Result<Self> {
+        let file = object::File::parse(data)?;
+        let symbols = file.symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| Some(Symbol {
+                address: symbol.address(),
+                size: (symbol.size() != 0).then_some(symbol.size()),
+                name: symbol.name().ok()?.to_string(),
+            }))
+            .collect();
+        // without debug info, there are no units and every lookup is empty
+        let dwarf = addr2line::Context::new(&file).ok();
+        Ok(Self::with_symbols(symbols, dwarf))
+    }
+
+    fn with_symbols(mut symbols: Vec<Symbol>, dwarf: Option<DwarfContext>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self { symbols, dwarf }
+    }
+
+    /// Resolve an instruction address. Returns None if it is not inside a known function.
+    pub fn symbolize(&self, address: u64) -> Option<Location> {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        let end = symbol.size.map(|size| symbol.address + size)
+            .or_else(|| self.symbols.get(index + 1).map(|next| next.address))?;
+        if address >= end {
+            return None;
+        }
+        let (file, line) = self.dwarf.as_ref()
+            .and_then(|dwarf| dwarf.find_location(address).ok().flatten())
+            .map_or((None, None), |location| (location.file.map(|file| file.to_string()), location.line));
+        Some(Location { function: symbol.name.clone(), offset: address - symbol.address, file, line })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_map() {
+        let map = "\
+            ffffffff81000000 T _stext\n\
+            ffffffff81000100 t zil_pmem_prb_write\n\
+            ffffffff81000180 D some_data\n\
+            ffffffff81000200 T zil_pmem_commit\n";
+        let symbolizer = Symbolizer::from_system_map(map.as_bytes()).unwrap();
+        let location = symbolizer.symbolize(0xffffffff8100014a).unwrap();
+        assert_eq!(location.to_string(), "zil_pmem_prb_write+0x4a");
+        // data symbols are skipped, so the function extends up to the next text symbol
+        assert_eq!(symbolizer.symbolize(0xffffffff810001f0).unwrap().function, "zil_pmem_prb_write");
+        assert_eq!(symbolizer.symbolize(0xffffffff80000000), None);
+        // the last symbol has no known end, e.g. _etext
+        assert_eq!(symbolizer.symbolize(0xffffffff81000210), None);
+    }
+}
@@ -1,7 +1,56 @@
-use std::io::{BufRead, Read, Write};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 
 use serde::{Serialize, Deserialize};
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use enumset::EnumSet;
+
+use crate::config::{capture_hashes, TcgPluginConfig, TraceOption};
+use crate::hash::ConfigHash;
+
+/// first bytes of every trace file (after decompression)
+const TRACE_MAGIC: &[u8; 8] = b"PERMTRCE";
+/// version of the trace format. Increment on every incompatible change of the header or entries.
+pub const TRACE_FORMAT_VERSION: u32 = 3;
+
+/// Describes how a trace was captured. Written after the magic number and format version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceHeader {
+    /// `permanent_trace_version` of the plugin that wrote the trace
+    pub plugin_version: i32,
+    pub pmem_start: u64,
+    pub pmem_len: u64,
+    pub trace_what: EnumSet<TraceOption>,
+    /// capture hashes of vm_config.yaml and test_config.yaml of the work dir, see [crate::config::capture_hashes]
+    pub vm_config_hash: Option<ConfigHash>,
+    pub test_config_hash: Option<ConfigHash>,
+}
+
+impl TraceHeader {
+    pub fn new(plugin_version: i32, conf: &TcgPluginConfig) -> Self {
+        Self {
+            plugin_version,
+            pmem_start: conf.pmem_start,
+            pmem_len: conf.pmem_len,
+            trace_what: conf.trace_what,
+            vm_config_hash: conf.vm_config_hash.clone(),
+            test_config_hash: conf.test_config_hash.clone(),
+        }
+    }
+
+    /// Make sure that the trace was captured with the current config files of the work dir.
+    /// Only the fields that change what a trace captures are compared, e.g. the `model` section may change.
+    pub fn check_work_dir(&self, work_dir: &String) -> Result<()> {
+        let (vm, test) = capture_hashes(work_dir)?;
+        for (name, recorded, current) in [("vm_config.yaml", &self.vm_config_hash, vm), ("test_config.yaml", &self.test_config_hash, test)] {
+            let Some(recorded) = recorded else { continue };
+            if current.as_ref() != Some(recorded) {
+                bail!("{}/{} has changed since the trace was captured. trace again.", work_dir, name);
+            }
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PmemEvent {
@@ -39,12 +88,27 @@ pub enum NvmeEvent {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TraceEntry {
-    Pmem { id: u64, event: PmemEvent },
+    Pmem {
+        id: u64,
+        event: PmemEvent,
+        /// guest virtual address of the instruction that caused the event
+        #[serde(default)]
+        rip: u64,
+        /// index of the vCPU that executed the instruction
+        #[serde(default)]
+        vcpu: u32,
+    },
     Nvme { id: u64, event: NvmeEvent },
     Checkpoint { id: u64, value: u8 },
 }
 
 impl TraceEntry {
+    pub fn id(&self) -> u64 {
+        match self {
+            TraceEntry::Pmem { id, .. } | TraceEntry::Nvme { id, .. } | TraceEntry::Checkpoint { id, .. } => *id,
+        }
+    }
+
     pub fn deserialize_from<R: Read>(src: &mut R) -> bincode::Result<TraceEntry> {
         bincode::deserialize_from(src)
     }
@@ -54,42 +118,642 @@ impl TraceEntry {
     }
 }
 
+/// Written after the last entry of a cleanly terminated trace. A trace without it was cut off,
+/// e.g. because QEMU was killed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceTrailer {
+    pub entry_count: u64,
+    /// checksum of all entries, see `Checksum`
+    pub checksum: u64,
+}
+
+/// Entries are wrapped, so that the trailer can be told apart from them.
+#[derive(Serialize)]
+enum RecordRef<'a> {
+    Entry(&'a TraceEntry),
+    Trailer(&'a TraceTrailer),
+}
+
+#[derive(Deserialize)]
+enum Record {
+    Entry(TraceEntry),
+    Trailer(TraceTrailer),
+}
+
+/// running FNV-1a checksum over the encoded records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+struct ChecksumReader<R: Read> {
+    inner: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    checksum: Checksum,
+    /// number of bytes written
+    written: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub struct BinTraceIterator<R: Read> {
-    file: R,
+    pub header: TraceHeader,
+    file: ChecksumReader<R>,
+    entry_count: u64,
+    terminated: bool,
+    done: bool,
+    /// entries before this id are skipped
+    skip_until: u64,
+    /// the trailer can only be verified when reading from the start
+    from_start: bool,
+    /// the trailer, once it has been read
+    trailer: Option<TraceTrailer>,
 }
 
-fn is_eof(err: &bincode::ErrorKind) -> bool {
-    match err {
-        bincode::ErrorKind::Io(io_err) => match io_err.kind() {
-            std::io::ErrorKind::UnexpectedEof => true,
-            _ => false,
-        },
-        _ => false,
+impl<R: Read> BinTraceIterator<R> {
+    /// Whether the trailer has been read and matches the entries, i.e. the trace is complete.
+    /// Only meaningful after the iterator has returned None.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
     }
 }
 
+fn is_eof(err: &bincode::ErrorKind) -> bool {
+    matches!(err, bincode::ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
 impl<R: Read> Iterator for BinTraceIterator<R> {
     type Item = Result<TraceEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match TraceEntry::deserialize_from(&mut self.file) {
-            Ok(e) => Some(Ok(e)),
-            Err(e) if is_eof(&*e) => None,
-            Err(e) => Some(Err(e.into())),
+        loop {
+            let entry = self.next_record();
+            match &entry {
+                Some(Ok(e)) if e.id() < self.skip_until => continue,
+                _ => return entry,
+            }
+        }
+    }
+}
+
+impl<R: Read> BinTraceIterator<R> {
+    fn next_record(&mut self) -> Option<Result<TraceEntry>> {
+        if self.done {
+            return None;
+        }
+        // the trailer contains the checksum of everything before it
+        let checksum = self.file.checksum;
+        match bincode::deserialize_from(&mut self.file) {
+            Ok(Record::Entry(e)) => {
+                self.entry_count += 1;
+                Some(Ok(e))
+            },
+            Ok(Record::Trailer(trailer)) => {
+                self.done = true;
+                let trailer = self.trailer.insert(trailer);
+                if !self.from_start {
+                    self.terminated = true;
+                    None
+                } else if trailer.entry_count != self.entry_count {
+                    Some(Err(anyhow!("trace trailer expects {} entries, but {} were read", trailer.entry_count, self.entry_count)))
+                } else if trailer.checksum != checksum.0 {
+                    Some(Err(anyhow!("trace checksum mismatch")))
+                } else {
+                    self.terminated = true;
+                    None
+                }
+            },
+            Err(e) if is_eof(&e) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            },
+        }
+    }
+}
+
+/// Write position counting wrapper, used to find the start of chunks in the compressed file.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A new chunk is started after this many uncompressed bytes.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Index of a trace, written next to it. Every chunk is compressed independently, so that
+/// reading can start at any chunk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TraceIndex {
+    pub chunks: Vec<TraceChunk>,
+    /// id of the last entry of the trace
+    pub last_id: Option<u64>,
+    /// trailer of the trace, identifies the trace the index belongs to
+    pub trailer: TraceTrailer,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TraceChunk {
+    /// position of the chunk in the compressed trace file
+    pub offset: u64,
+    /// id of the first entry in the chunk
+    pub first_id: u64,
+    /// values and entry ids of the checkpoints in the chunk
+    pub checkpoints: Vec<(u8, u64)>,
+}
+
+impl TraceIndex {
+    /// entry id of the first checkpoint with the given value
+    pub fn checkpoint_id(&self, value: u8) -> Option<u64> {
+        self.chunks.iter()
+            .flat_map(|chunk| chunk.checkpoints.iter())
+            .find(|(other, _)| *other == value)
+            .map(|(_, id)| *id)
+    }
+
+    /// the chunk that contains the entry with the given id
+    fn chunk_of(&self, id: u64) -> Option<&TraceChunk> {
+        self.last_id.filter(|last| id <= *last)?;
+        self.chunks.iter().take_while(|chunk| chunk.first_id <= id).last()
+    }
+}
+
+pub fn index_path(trace_path: &str) -> String {
+    format!("{}.idx", trace_path)
+}
+
+struct IndexBuilder {
+    path: String,
+    index: TraceIndex,
+    /// start of the next chunk, which is created with its first entry
+    next_offset: Option<u64>,
+    chunk_start: u64,
+}
+
+pub struct TraceWriter<W: Write> {
+    out: ChecksumWriter<snap::write::FrameEncoder<CountingWriter<W>>>,
+    entry_count: u64,
+    index: Option<IndexBuilder>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Split the trace into chunks and write an index at `index_path` when finishing.
+    pub fn with_index(mut self, index_path: String) -> Result<Self> {
+        // the first chunk starts after the header
+        self.out.inner.flush()?;
+        self.index = Some(IndexBuilder {
+            path: index_path,
+            index: TraceIndex::default(),
+            next_offset: Some(self.out.inner.get_ref().count),
+            chunk_start: self.out.written,
+        });
+        Ok(self)
+    }
+
+    pub fn write_entry(&mut self, entry: &TraceEntry) -> Result<()> {
+        if let Some(builder) = self.index.as_mut() {
+            if let Some(offset) = builder.next_offset.take() {
+                builder.index.chunks.push(TraceChunk { offset, first_id: entry.id(), checkpoints: Vec::new() });
+            }
+            if let TraceEntry::Checkpoint { id, value } = entry {
+                builder.index.chunks.last_mut().unwrap().checkpoints.push((*value, *id));
+            }
+            builder.index.last_id = Some(entry.id());
+        }
+        bincode::serialize_into(&mut self.out, &RecordRef::Entry(entry))?;
+        self.entry_count += 1;
+        if let Some(builder) = self.index.as_mut() {
+            if self.out.written - builder.chunk_start >= CHUNK_SIZE {
+                // compress everything so far, so that the next chunk starts in a new frame
+                self.out.inner.flush()?;
+                builder.next_offset = Some(self.out.inner.get_ref().count);
+                builder.chunk_start = self.out.written;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the trailer and flush. Without it, readers consider the trace truncated.
+    pub fn finish(mut self) -> Result<()> {
+        let trailer = TraceTrailer { entry_count: self.entry_count, checksum: self.out.checksum.0 };
+        bincode::serialize_into(&mut self.out, &RecordRef::Trailer(&trailer))?;
+        self.out.flush()?;
+        self.out.inner.get_mut().flush()?;
+        // the index is only written for complete traces
+        if let Some(mut builder) = self.index {
+            builder.index.trailer = trailer;
+            let file = io::BufWriter::new(File::create(builder.path.as_str())?);
+            bincode::serialize_into(file, &builder.index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Create a trace writer with compression and write the header.
+pub fn new_trace_writer_bin<W: Write>(file: W, header: &TraceHeader) -> Result<TraceWriter<W>> {
+    let mut writer = snap::write::FrameEncoder::new(CountingWriter { inner: file, count: 0 });
+    writer.write_all(TRACE_MAGIC)?;
+    writer.write_all(&TRACE_FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, header)?;
+    Ok(TraceWriter {
+        out: ChecksumWriter { inner: writer, checksum: Checksum::new(), written: 0 },
+        entry_count: 0,
+        index: None,
+    })
+}
+
+/// Parse a binary trace file. Fails if the header is missing or has a different format version.
+/// Use `is_terminated` to find out whether the trace is complete.
+pub fn parse_trace_file_bin<R: BufRead>(file: R) -> Result<BinTraceIterator<snap::read::FrameDecoder<R>>> {
+    let mut file = snap::read::FrameDecoder::new(file);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic).context("could not read trace header")?;
+    if &magic != TRACE_MAGIC {
+        bail!("not a permanent trace or written by a version without trace header");
+    }
+    let mut version = [0u8; 4];
+    file.read_exact(&mut version).context("could not read trace header")?;
+    let version = u32::from_le_bytes(version);
+    if version != TRACE_FORMAT_VERSION {
+        bail!("trace format version {} is not supported (expected {}). trace again.", version, TRACE_FORMAT_VERSION);
+    }
+    let header = bincode::deserialize_from(&mut file).context("could not parse trace header")?;
+    Ok(BinTraceIterator {
+        header,
+        file: ChecksumReader { inner: file, checksum: Checksum::new() },
+        entry_count: 0,
+        terminated: false,
+        done: false,
+        skip_until: 0,
+        from_start: true,
+        trailer: None,
+    })
+}
+
+/// the stream identifier that starts every snappy frame stream
+const SNAPPY_STREAM_IDENTIFIER: &[u8] = b"\xFF\x06\x00\x00sNaPpY";
+
+pub type SeekedTraceIterator = BinTraceIterator<snap::read::FrameDecoder<io::Chain<&'static [u8], BufReader<File>>>>;
+
+/// A trace with index for random access by entry id.
+pub struct IndexedTrace {
+    path: String,
+    pub header: TraceHeader,
+    pub index: TraceIndex,
+}
+
+impl IndexedTrace {
+    /// Open a trace and its index. Fails if the trace has no index or the index belongs to a
+    /// different trace, e.g. one that was captured again.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("could not open trace {}", path))?;
+        let mut entries = parse_trace_file_bin(BufReader::new(file))?;
+        let index_path = index_path(path);
+        let index_file = File::open(index_path.as_str()).with_context(|| format!("could not open trace index {}", index_path))?;
+        let index: TraceIndex = bincode::deserialize_from(BufReader::new(index_file)).context("could not parse trace index")?;
+        let trace = Self { path: path.to_string(), header: entries.header.clone(), index };
+        // only the last chunk is read to find the trailer
+        let trailer = match trace.index.last_id {
+            Some(last_id) => read_trailer(&mut trace.seek_to_id(last_id)?)?,
+            None => read_trailer(&mut entries)?,
+        };
+        if trailer.as_ref() != Some(&trace.index.trailer) {
+            bail!("trace index {} does not belong to the trace", index_path);
+        }
+        Ok(trace)
+    }
+
+    /// Read the trace starting at the entry with the given id, without decoding the chunks before.
+    /// The trailer is not verified.
+    pub fn seek_to_id(&self, id: u64) -> Result<SeekedTraceIterator> {
+        let chunk = self.index.chunk_of(id).with_context(|| format!("entry {} is not in the trace", id))?;
+        let mut file = File::open(self.path.as_str())?;
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        // chunks start in a new frame, so decoding works after prepending the stream identifier
+        let file = SNAPPY_STREAM_IDENTIFIER.chain(BufReader::new(file));
+        Ok(BinTraceIterator {
+            header: self.header.clone(),
+            file: ChecksumReader { inner: snap::read::FrameDecoder::new(file), checksum: Checksum::new() },
+            entry_count: 0,
+            terminated: false,
+            done: false,
+            skip_until: id,
+            from_start: false,
+            trailer: None,
+        })
+    }
+
+    /// All entries from checkpoint `start` to checkpoint `end`, including both checkpoints.
+    pub fn entries_between_checkpoints(&self, start: u8, end: u8) -> Result<impl Iterator<Item = Result<TraceEntry>>> {
+        let start_id = self.index.checkpoint_id(start).with_context(|| format!("checkpoint {} is not in the trace", start))?;
+        let end_id = self.index.checkpoint_id(end).with_context(|| format!("checkpoint {} is not in the trace", end))?;
+        if end_id < start_id {
+            bail!("checkpoint {} comes before checkpoint {}", end, start);
+        }
+        Ok(self.seek_to_id(start_id)?
+            .take_while(move |entry| entry.as_ref().map_or(true, |entry| entry.id() <= end_id)))
+    }
+}
+
+/// Read the remaining entries and return the trailer, if the trace has one.
+fn read_trailer<R: Read>(entries: &mut BinTraceIterator<R>) -> Result<Option<TraceTrailer>> {
+    for entry in entries.by_ref() {
+        entry?;
+    }
+    Ok(entries.trailer.take())
+}
+
+/// Header line of the text format. Missing fields get defaults, so that hand-written traces only
+/// need to state what matters for them.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TextHeader {
+    plugin_version: i32,
+    pmem_start: u64,
+    pmem_len: u64,
+    /// names as in the plugin arguments, e.g. `pmem_write`
+    trace_what: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vm_config_hash: Option<ConfigHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test_config_hash: Option<ConfigHash>,
+}
+
+impl Default for TextHeader {
+    fn default() -> Self {
+        Self {
+            plugin_version: 0,
+            pmem_start: 0,
+            pmem_len: 0,
+            trace_what: EnumSet::<TraceOption>::all().iter().map(|o| o.to_qemu_str().to_string()).collect(),
+            vm_config_hash: None,
+            test_config_hash: None,
         }
     }
 }
 
-pub type TraceWriter<W> = snap::write::FrameEncoder<W>;
+/// Parse a trace in the text format: JSON Lines with the header in the first line and one entry
+/// per following line, in the serde representation of `TraceEntry`. Empty lines and lines starting
+/// with `#` are ignored.
+pub fn parse_trace_file_text<R: BufRead>(file: R) -> Result<(TraceHeader, Vec<TraceEntry>)> {
+    let mut header = None;
+    let mut entries: Vec<TraceEntry> = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("line {}", i + 1);
+        if header.is_none() {
+            let text: TextHeader = serde_json::from_str(line).with_context(context)?;
+            let mut trace_what = EnumSet::new();
+            for name in text.trace_what.iter() {
+                trace_what |= TraceOption::from_qemu_str(name).map_err(|_| anyhow!("unknown trace option {}", name)).with_context(context)?;
+            }
+            header = Some(TraceHeader {
+                plugin_version: text.plugin_version,
+                pmem_start: text.pmem_start,
+                pmem_len: text.pmem_len,
+                trace_what,
+                vm_config_hash: text.vm_config_hash,
+                test_config_hash: text.test_config_hash,
+            });
+            continue;
+        }
+        let entry: TraceEntry = serde_json::from_str(line).with_context(context)?;
+        // the index relies on increasing ids
+        if entries.last().is_some_and(|prev| prev.id() >= entry.id()) {
+            return Err(anyhow!("entry ids must be increasing")).with_context(context);
+        }
+        entries.push(entry);
+    }
+    Ok((header.context("trace has no header")?, entries))
+}
 
-/// Create a trace writer with compression.
-pub fn new_trace_writer_bin<W: Write>(file: W) -> TraceWriter<W> {
-    snap::write::FrameEncoder::new(file)
+/// Write a trace in the text format, see `parse_trace_file_text`.
+pub fn write_trace_file_text<W: Write>(mut dst: W, header: &TraceHeader, entries: impl Iterator<Item = Result<TraceEntry>>) -> Result<()> {
+    let text = TextHeader {
+        plugin_version: header.plugin_version,
+        pmem_start: header.pmem_start,
+        pmem_len: header.pmem_len,
+        trace_what: header.trace_what.iter().map(|o| o.to_qemu_str().to_string()).collect(),
+        vm_config_hash: header.vm_config_hash.clone(),
+        test_config_hash: header.test_config_hash.clone(),
+    };
+    serde_json::to_writer(&mut dst, &text)?;
+    writeln!(dst)?;
+    for entry in entries {
+        serde_json::to_writer(&mut dst, &entry?)?;
+        writeln!(dst)?;
+    }
+    dst.flush()?;
+    Ok(())
 }
 
-/// Parse a binary trace file.
-pub fn parse_trace_file_bin<R: BufRead>(file: R) -> BinTraceIterator<snap::read::FrameDecoder<R>> {
-    BinTraceIterator {
-        file: snap::read::FrameDecoder::new(file),
+/// Convert a trace in the text format to a binary trace, optionally with index.
+pub fn import_trace_text(src: &str, dst: &str, index: bool) -> Result<()> {
+    let file = File::open(src).with_context(|| format!("could not open {}", src))?;
+    let (header, entries) = parse_trace_file_text(BufReader::new(file)).with_context(|| format!("invalid text trace {}", src))?;
+    let file = io::BufWriter::new(File::create(dst).with_context(|| format!("could not create {}", dst))?);
+    let mut writer = new_trace_writer_bin(file, &header)?;
+    if index {
+        writer = writer.with_index(index_path(dst))?;
+    } else {
+        // an index of a previous trace at dst does not fit the new one
+        match std::fs::remove_file(index_path(dst)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).context("could not remove the old trace index"),
+            _ => {},
+        }
+    }
+    for entry in entries.iter() {
+        writer.write_entry(entry)?;
+    }
+    writer.finish()
+}
+
+/// Convert a binary trace to the text format. Returns false if the binary trace is truncated, in
+/// which case all entries up to the cut are converted.
+pub fn export_trace_text(src: &str, dst: &str) -> Result<bool> {
+    let file = File::open(src).with_context(|| format!("could not open {}", src))?;
+    let mut trace = parse_trace_file_bin(BufReader::new(file))?;
+    let file = io::BufWriter::new(File::create(dst).with_context(|| format!("could not create {}", dst))?);
+    let header = trace.header.clone();
+    write_trace_file_text(file, &header, trace.by_ref())?;
+    Ok(trace.is_terminated())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> TraceHeader {
+        TraceHeader {
+            plugin_version: 1,
+            pmem_start: 512,
+            pmem_len: 128,
+            trace_what: TraceOption::PmemWrite | TraceOption::Checkpoint,
+            vm_config_hash: Some(ConfigHash::of(b"vm")),
+            test_config_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut buf = Vec::new();
+        let mut writer = new_trace_writer_bin(&mut buf, &header()).unwrap();
+        writer.write_entry(&TraceEntry::Checkpoint { id: 0, value: 255 }).unwrap();
+        writer.finish().unwrap();
+
+        let mut trace = parse_trace_file_bin(buf.as_slice()).unwrap();
+        assert_eq!(trace.header.pmem_start, 512);
+        assert_eq!(trace.header.trace_what, TraceOption::PmemWrite | TraceOption::Checkpoint);
+        assert_eq!(trace.header.vm_config_hash, Some(ConfigHash::of(b"vm")));
+        let entries: Vec<TraceEntry> = trace.by_ref().map(|entry| entry.unwrap()).collect();
+        assert!(matches!(entries.as_slice(), [TraceEntry::Checkpoint { id: 0, value: 255 }]));
+        assert!(trace.is_terminated());
+    }
+
+    #[test]
+    fn test_seek() {
+        let dir = std::env::temp_dir().join(format!("permanent_trace_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.bin").to_str().unwrap().to_string();
+
+        let file = File::create(path.as_str()).unwrap();
+        let mut writer = new_trace_writer_bin(file, &header()).unwrap().with_index(index_path(path.as_str())).unwrap();
+        let content = vec![0u8; 4096];
+        let mut id = 0;
+        for value in 0..3 {
+            writer.write_entry(&TraceEntry::Checkpoint { id, value }).unwrap();
+            id += 1;
+            // enough data for a chunk between checkpoints
+            for _ in 0..300 {
+                let event = PmemEvent::Write { address: id, size: 4096, content: content.clone(), non_temporal: false };
+                writer.write_entry(&TraceEntry::Pmem { id, event, rip: 0, vcpu: 0 }).unwrap();
+                id += 1;
+            }
+        }
+        writer.finish().unwrap();
+
+        let trace = IndexedTrace::open(path.as_str()).unwrap();
+        assert!(trace.index.chunks.len() > 2);
+        assert_eq!(trace.index.checkpoint_id(2), Some(602));
+
+        let mut entries = trace.seek_to_id(500).unwrap();
+        assert_eq!(entries.next().unwrap().unwrap().id(), 500);
+        assert_eq!(entries.by_ref().count(), id as usize - 501);
+        assert!(entries.is_terminated());
+
+        let ids: Vec<u64> = trace.entries_between_checkpoints(1, 2).unwrap().map(|entry| entry.unwrap().id()).collect();
+        assert_eq!(ids, (301..=602).collect::<Vec<_>>());
+        assert!(trace.seek_to_id(id).is_err());
+
+        // a trace captured again must not be read with the old index
+        let mut writer = new_trace_writer_bin(File::create(path.as_str()).unwrap(), &header()).unwrap();
+        writer.write_entry(&TraceEntry::Checkpoint { id: 0, value: 0 }).unwrap();
+        writer.finish().unwrap();
+        assert!(IndexedTrace::open(path.as_str()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut buf = Vec::new();
+        let mut writer = new_trace_writer_bin(&mut buf, &header()).unwrap();
+        writer.write_entry(&TraceEntry::Checkpoint { id: 0, value: 255 }).unwrap();
+        writer.write_entry(&TraceEntry::Checkpoint { id: 1, value: 0 }).unwrap();
+        // dropped without trailer, like a writer that panicked
+        drop(writer);
+
+        let mut trace = parse_trace_file_bin(buf.as_slice()).unwrap();
+        assert_eq!(trace.by_ref().count(), 2);
+        assert!(!trace.is_terminated());
+    }
+
+    #[test]
+    fn test_missing_header() {
+        let mut buf = Vec::new();
+        let mut writer = snap::write::FrameEncoder::new(&mut buf);
+        TraceEntry::Checkpoint { id: 0, value: 255 }.serialize_into(&mut writer).unwrap();
+        drop(writer);
+
+        let err = parse_trace_file_bin(buf.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("without trace header"));
+    }
+
+    #[test]
+    fn test_text() {
+        let text = r#"
+            # defaults for everything but pmem_len
+            {"pmem_len": 4096}
+            {"Checkpoint": {"id": 0, "value": 255}}
+            {"Pmem": {"id": 1, "event": {"Write": {"address": 8, "size": 2, "content": [1, 2], "non_temporal": false}}}}
+            {"Pmem": {"id": 2, "event": "Fence"}}
+        "#;
+        let (parsed, entries) = parse_trace_file_text(text.as_bytes()).unwrap();
+        assert_eq!(parsed.pmem_len, 4096);
+        assert_eq!(parsed.trace_what, EnumSet::all());
+        assert_eq!(entries.iter().map(|entry| entry.id()).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let mut buf = Vec::new();
+        write_trace_file_text(&mut buf, &header(), entries.into_iter().map(Ok)).unwrap();
+        let (parsed, entries) = parse_trace_file_text(buf.as_slice()).unwrap();
+        assert_eq!(parsed.trace_what, header().trace_what);
+        assert_eq!(parsed.vm_config_hash, header().vm_config_hash);
+        assert!(matches!(entries[1], TraceEntry::Pmem { id: 1, event: PmemEvent::Write { address: 8, .. }, .. }));
+
+        let unordered = "{}\n{\"Checkpoint\": {\"id\": 1, \"value\": 0}}\n{\"Checkpoint\": {\"id\": 1, \"value\": 1}}\n";
+        let err = parse_trace_file_text(unordered.as_bytes()).err().unwrap();
+        assert!(format!("{:#}", err).contains("line 3"));
     }
 }
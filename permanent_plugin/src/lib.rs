@@ -9,7 +9,7 @@ use crossbeam_channel::Sender;
 use enumset::EnumSet;
 
 use permanent_common::config::{TraceOption, TcgPluginConfig};
-use permanent_common::trace::{PmemEvent, new_trace_writer_bin};
+use permanent_common::trace::{PmemEvent, TraceHeader, index_path, new_trace_writer_bin};
 
 mod qemu_plugin_bindings;
 use qemu_plugin_bindings as qp;
@@ -24,19 +24,20 @@ pub static permanent_trace_version: ffi::c_int = 1;
 
 //------------------------------------------------------------------------------
 
+// rip is the guest virtual address of the instruction
 #[derive(Debug)]
 enum UserdataMem {
-    ReadWrite { disas: String, nt: bool },
-    Clflush { disas: String },
-    Clflushopt { disas: String },
-    Clwb { disas: String },
+    ReadWrite { disas: String, rip: u64, nt: bool },
+    Clflush { disas: String, rip: u64 },
+    Clflushopt { disas: String, rip: u64 },
+    Clwb { disas: String, rip: u64 },
     Checkpoint,
 }
 
 #[derive(Debug)]
 enum UserdataExec {
-    Wbinvd { disas: String },
-    Fence { disas: String },
+    Wbinvd { disas: String, rip: u64 },
+    Fence { disas: String, rip: u64 },
 }
 
 struct WriterThread {
@@ -63,6 +64,10 @@ fn get_conf() -> &'static TcgPluginConfig {
     &CONFIG.get().unwrap()
 }
 
+fn send_pmem_msg(event: PmemEvent, rip: u64, vcpu_index: ffi::c_uint) {
+    send_msg(TraceMessage::Pmem { event, rip, vcpu: vcpu_index });
+}
+
 fn send_msg(msg: TraceMessage) {
     WRITER_THREAD.lock().unwrap()
             .as_mut().unwrap()
@@ -71,22 +76,22 @@ fn send_msg(msg: TraceMessage) {
 }
 
 #[no_mangle]
-extern "C" fn my_vcpu_insn_exec_cb(_vcpu_index: ffi::c_uint, userdata: *mut ffi::c_void) {
+extern "C" fn my_vcpu_insn_exec_cb(vcpu_index: ffi::c_uint, userdata: *mut ffi::c_void) {
     let u: &UserdataExec = unsafe { &*(userdata as *const UserdataExec) };
     
     // filtering happens in hook_insn
     match u {
-        UserdataExec::Wbinvd { .. } => {
+        UserdataExec::Wbinvd { disas: _, rip } => {
             // *HAVE_WRITES.lock().unwrap() = true;
-            send_msg(TraceMessage::Pmem(PmemEvent::Wbinvd));
+            send_pmem_msg(PmemEvent::Wbinvd, *rip, vcpu_index);
         },
-        UserdataExec::Fence { .. } => {
+        UserdataExec::Fence { disas: _, rip } => {
             // let mut have_writes = HAVE_WRITES.lock().unwrap();
             // if *have_writes {
             //     *have_writes = false;
             //     send_msg(TraceMessage::Pmem(PmemEvent::Fence));
             // }
-            send_msg(TraceMessage::Pmem(PmemEvent::Fence));
+            send_pmem_msg(PmemEvent::Fence, *rip, vcpu_index);
         },
     }
 }
@@ -122,19 +127,19 @@ extern "C" fn my_vcpu_mem_cb(vcpu_index: ffi::c_uint, info: qp::QemuPluginMeminf
     match u {
         // filtering of checkpoint and flush happens in hook_insn
         UserdataMem::Checkpoint => panic!("checkpoints handled above"),
-        UserdataMem::Clflush { .. } => {
+        UserdataMem::Clflush { disas: _, rip } => {
             // *HAVE_WRITES.lock().unwrap() = true;
-            send_msg(TraceMessage::Pmem(PmemEvent::Clflush { address }));
+            send_pmem_msg(PmemEvent::Clflush { address }, *rip, vcpu_index);
         },
-        UserdataMem::Clflushopt { .. } => {
+        UserdataMem::Clflushopt { disas: _, rip } => {
             // *HAVE_WRITES.lock().unwrap() = true;
-            send_msg(TraceMessage::Pmem(PmemEvent::Clflushopt { address }));
+            send_pmem_msg(PmemEvent::Clflushopt { address }, *rip, vcpu_index);
         },
-        UserdataMem::Clwb { .. } => {
+        UserdataMem::Clwb { disas: _, rip } => {
             // *HAVE_WRITES.lock().unwrap() = true;
-            send_msg(TraceMessage::Pmem(PmemEvent::Clwb { address }));
+            send_pmem_msg(PmemEvent::Clwb { address }, *rip, vcpu_index);
         },
-        UserdataMem::ReadWrite { disas: _, nt: is_nt } => {
+        UserdataMem::ReadWrite { disas: _, rip, nt: is_nt } => {
             let is_store = unsafe { qp::qemu_plugin_mem_is_store(info) };
             if (is_store && conf.trace_what.contains(TraceOption::PmemWrite))
                     || (!is_store && conf.trace_what.contains(TraceOption::PmemRead)) {
@@ -148,9 +153,9 @@ extern "C" fn my_vcpu_mem_cb(vcpu_index: ffi::c_uint, info: qp::QemuPluginMeminf
                 }
 
                 if is_store {
-                    send_msg(TraceMessage::Pmem(PmemEvent::Write { address, size: nb as u64, content: buf, non_temporal: *is_nt }));
+                    send_pmem_msg(PmemEvent::Write { address, size: nb as u64, content: buf, non_temporal: *is_nt }, *rip, vcpu_index);
                 } else {
-                    send_msg(TraceMessage::Pmem(PmemEvent::Read { address, size: nb as u64, content: buf }));
+                    send_pmem_msg(PmemEvent::Read { address, size: nb as u64, content: buf }, *rip, vcpu_index);
                 }
             }
         },
@@ -191,12 +196,13 @@ fn hook_insn(insn: *mut qp::QemuPluginInsn) {
         return;
     }
     let disas = decoded_insn.to_string();
+    let rip = unsafe { qp::qemu_plugin_insn_vaddr(insn) };
     
     let maybe_exec_udat = match decoded_insn.mnemonic() {
         Mnemonic::Wbinvd => conf.trace_what.contains(TraceOption::PmemFlush)
-                .then(|| Box::new(UserdataExec::Wbinvd { disas: disas.clone() })),
+                .then(|| Box::new(UserdataExec::Wbinvd { disas: disas.clone(), rip })),
         Mnemonic::Mfence | Mnemonic::Sfence => conf.trace_what.contains(TraceOption::PmemFence)
-                .then(|| Box::new(UserdataExec::Fence { disas: disas.clone() })),
+                .then(|| Box::new(UserdataExec::Fence { disas: disas.clone(), rip })),
         _ => None
     };
 
@@ -218,11 +224,11 @@ fn hook_insn(insn: *mut qp::QemuPluginInsn) {
                                                                             // because we use it
                                                                             // for pmem init
         Mnemonic::Clflush => conf.trace_what.contains(TraceOption::PmemFlush)
-                .then(|| Box::new(UserdataMem::Clflush { disas })),
+                .then(|| Box::new(UserdataMem::Clflush { disas, rip })),
         Mnemonic::Clflushopt => conf.trace_what.contains(TraceOption::PmemFlush)
-                .then(|| Box::new(UserdataMem::Clflushopt { disas })),
+                .then(|| Box::new(UserdataMem::Clflushopt { disas, rip })),
         Mnemonic::Clwb => conf.trace_what.contains(TraceOption::PmemFlush)
-                .then(|| Box::new(UserdataMem::Clwb { disas })),
+                .then(|| Box::new(UserdataMem::Clwb { disas, rip })),
         Mnemonic::Movntdq
         | Mnemonic::Movntdqa
         | Mnemonic::Movnti
@@ -231,8 +237,8 @@ fn hook_insn(insn: *mut qp::QemuPluginInsn) {
         | Mnemonic::Movntq
         | Mnemonic::Movntsd
         | Mnemonic::Movntss
-            => trace_rw.then(|| Box::new(UserdataMem::ReadWrite { disas, nt: true })),
-        _ => trace_rw.then(|| Box::new(UserdataMem::ReadWrite { disas, nt: false })),
+            => trace_rw.then(|| Box::new(UserdataMem::ReadWrite { disas, rip, nt: true })),
+        _ => trace_rw.then(|| Box::new(UserdataMem::ReadWrite { disas, rip, nt: false })),
     };
 
     if let Some(mut mem_udat) = maybe_mem_udat {
@@ -320,6 +326,9 @@ pub extern "C" fn qemu_plugin_install(
         pmem_base_image_path: None,
         trace_what: EnumSet::empty(),
         out_trace_file: String::new(),
+        trace_index: false,
+        vm_config_hash: None,
+        test_config_hash: None,
     };
     for arg in args {
         let (key, value) = arg.split_once("=").expect("invalid argument");
@@ -340,6 +349,9 @@ pub extern "C" fn qemu_plugin_install(
             "out_trace_file" => {
                 conf.out_trace_file = value.to_string();
             },
+            "trace_index" => { conf.trace_index = value == "on"; },
+            "vm_config_hash" => { conf.vm_config_hash = Some(value.parse().expect("invalid vm_config_hash")); },
+            "test_config_hash" => { conf.test_config_hash = Some(value.parse().expect("invalid test_config_hash")); },
             _ => panic!("unknown argument: {}", key),
         }
     }
@@ -351,7 +363,12 @@ pub extern "C" fn qemu_plugin_install(
         qp::qemu_plugin_register_atexit_cb(id, Some(my_atexit_cb), std::ptr::null_mut::<ffi::c_void>());
     }
 
-    let trace_out = new_trace_writer_bin(File::create(&conf.out_trace_file).expect("could not open out_trace_file"));
+    let header = TraceHeader::new(permanent_trace_version, &conf);
+    let mut trace_out = new_trace_writer_bin(File::create(&conf.out_trace_file).expect("could not open out_trace_file"), &header)
+        .expect("could not write trace header");
+    if conf.trace_index {
+        trace_out = trace_out.with_index(index_path(&conf.out_trace_file)).expect("could not start trace index");
+    }
     CONFIG.set(conf).expect("could not set config");
 
     // create writer thread
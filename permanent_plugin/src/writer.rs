@@ -6,7 +6,11 @@ use permanent_common::trace::{PmemEvent, NvmeEvent, TraceEntry, TraceWriter};
 
 #[derive(Debug)]
 pub enum TraceMessage {
-    Pmem(PmemEvent),
+    Pmem {
+        event: PmemEvent,
+        rip: u64,
+        vcpu: u32,
+    },
     NvmeFlush,
     PciNvmeBlkRead {
         req: u64,
@@ -53,11 +57,11 @@ struct TraceQueue<W: Write> {
     trace_out: TraceWriter<W>
 }
 
-fn write_entry<W: Write>(entry: TraceEntry, dst: &mut W) {
+fn write_entry<W: Write>(entry: TraceEntry, dst: &mut TraceWriter<W>) {
     if cfg!(permanent_trace_debug = "entry") {
         println!("{:?}", entry);
     } else {
-        entry.serialize_into(dst).expect("failed encoding trace entry");
+        dst.write_entry(&entry).expect("failed encoding trace entry");
     }
 }
 
@@ -87,8 +91,8 @@ impl<W: Write> TraceQueue<W> {
             TraceMessage::Checkpoint { value } => {
                 self.insert_complete(TraceEntry::Checkpoint { id: id64, value });
             },
-            TraceMessage::Pmem(event) => {
-                self.insert_complete(TraceEntry::Pmem { id: id64, event });
+            TraceMessage::Pmem { event, rip, vcpu } => {
+                self.insert_complete(TraceEntry::Pmem { id: id64, event, rip, vcpu });
             },
             TraceMessage::NvmeFlush => {
                 self.insert_complete(TraceEntry::Nvme { id: id64, event: NvmeEvent::Flush });
@@ -166,9 +170,10 @@ pub fn writer_main<W: Write>(trace_recv: Receiver<TraceMessage>, done_recv: Rece
                 if q.queue.len() > 0 {
                     panic!("writer thread quit with queue non-empty");
                 }
-                q.trace_out.flush().unwrap();
                 break;
             }
         }
     }
+    // a trace without trailer is considered truncated
+    q.trace_out.finish().expect("could not finish trace");
 }
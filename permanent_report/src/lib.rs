@@ -0,0 +1,383 @@
+//! Analysis of a finished test run: number of semantic states per logical operation
+//! (atomicity) and single final state (SFS) at every checkpoint.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use permanent_common::config::{TestConfig, VmConfig};
+use permanent_common::hash::{CrashHash, StateHash, FAILED_STATE_DUMP};
+
+pub mod summary;
+
+/// crash images that were booted together in a single post failure run
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CrashImages {
+    pub pmem: Option<CrashHash>,
+    pub nvme: Option<CrashHash>,
+}
+
+impl CrashImages {
+    /// Parse a name from states.index. Hybrid runs use `{pmem}_{nvme}`, all others a single hash.
+    pub fn parse(name: &str, have_pmem: bool, have_nvme: bool) -> Result<Self> {
+        let parse_hash = |s: &str| s.parse::<CrashHash>().with_context(|| format!("invalid crash hash {}", s));
+        match (have_pmem, have_nvme) {
+            (true, true) => {
+                let (pmem, nvme) = name.split_once('_')
+                    .with_context(|| format!("expected pmem and nvme hash in {}", name))?;
+                Ok(Self { pmem: Some(parse_hash(pmem)?), nvme: Some(parse_hash(nvme)?) })
+            },
+            (true, false) => Ok(Self { pmem: Some(parse_hash(name)?), nvme: None }),
+            (false, true) => Ok(Self { pmem: None, nvme: Some(parse_hash(name)?) }),
+            (false, false) => bail!("neither pmem nor nvme in use"),
+        }
+    }
+}
+
+impl fmt::Display for CrashImages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.pmem, &self.nvme) {
+            (Some(pmem), Some(nvme)) => write!(f, "{}_{}", pmem, nvme),
+            (Some(hash), None) | (None, Some(hash)) => write!(f, "{}", hash),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Serialize for CrashImages {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// contents of the index files of a work dir
+pub struct Indices {
+    /// checkpoint value -> trace entry id
+    pub checkpoints: BTreeMap<u8, usize>,
+    /// trace entry id -> pmem crash images generated at that id
+    pub pmem: Option<BTreeMap<usize, BTreeSet<CrashHash>>>,
+    /// trace entry id -> nvme crash images generated at that id
+    pub nvme: Option<BTreeMap<usize, BTreeSet<CrashHash>>>,
+    /// semantic state -> crash images that resulted in that state
+    pub states: BTreeMap<StateHash, Vec<CrashImages>>,
+}
+
+fn read_index<T: DeserializeOwned>(work_dir: &String, name: &str) -> Result<T> {
+    let path = format!("{}/{}", work_dir, name);
+    let file = File::open(path.as_str()).with_context(|| format!("could not open {}", path))?;
+    serde_json::from_reader(BufReader::new(file)).with_context(|| format!("could not parse {}", path))
+}
+
+impl Indices {
+    pub fn read(work_dir: &String, vm_config: &VmConfig) -> Result<Self> {
+        let (p, n) = vm_config.have_pmem_nvme();
+        let states: BTreeMap<StateHash, Vec<String>> = read_index(work_dir, "states.index")?;
+        let states = states.into_iter()
+            .map(|(state, names)| {
+                let images = names.iter()
+                    .map(|name| CrashImages::parse(name.as_str(), p, n))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((state, images))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            checkpoints: read_index(work_dir, "checkpoint.index")?,
+            pmem: p.then(|| read_index(work_dir, "pmem.index")).transpose()?,
+            nvme: n.then(|| read_index(work_dir, "nvme.index")).transpose()?,
+            states,
+        })
+    }
+
+    /// trace entry ids at which a crash image was generated
+    fn image_times(index: &BTreeMap<usize, BTreeSet<CrashHash>>) -> HashMap<&CrashHash, BTreeSet<usize>> {
+        let mut times: HashMap<&CrashHash, BTreeSet<usize>> = HashMap::new();
+        for (id, hashes) in index {
+            for hash in hashes {
+                times.entry(hash).or_default().insert(*id);
+            }
+        }
+        times
+    }
+
+    /// trace entry ids at which the crash images of each state were generated
+    pub fn crash_times(&self) -> Vec<(&StateHash, &CrashImages, BTreeSet<usize>)> {
+        let pmem_times = self.pmem.as_ref().map(Self::image_times);
+        let nvme_times = self.nvme.as_ref().map(Self::image_times);
+        let times_of = |images: &CrashImages| -> BTreeSet<usize> {
+            let pmem = images.pmem.as_ref().map(|hash| {
+                pmem_times.as_ref().and_then(|times| times.get(hash)).cloned().unwrap_or_default()
+            });
+            let nvme = images.nvme.as_ref().map(|hash| {
+                nvme_times.as_ref().and_then(|times| times.get(hash)).cloned().unwrap_or_default()
+            });
+            match (pmem, nvme) {
+                // in hybrid runs, both images must have been generated at the same point
+                (Some(pmem), Some(nvme)) => pmem.intersection(&nvme).copied().collect(),
+                (Some(times), None) | (None, Some(times)) => times,
+                (None, None) => BTreeSet::new(),
+            }
+        };
+
+        self.states.iter()
+            .flat_map(|(state, images)| images.iter().map(move |images| (state, images)))
+            .map(|(state, images)| (state, images, times_of(images)))
+            .collect()
+    }
+}
+
+/// semantic states and the crash images that resulted in them
+pub type States = BTreeMap<StateHash, BTreeSet<CrashImages>>;
+
+/// semantic states of the logical operation between two checkpoints
+pub struct IntervalReport {
+    pub start: u8,
+    pub end: u8,
+    pub states: States,
+    /// whether the test expects the operation to be atomic
+    pub expect_atomic: bool,
+}
+
+impl IntervalReport {
+    /// the operation is atomic if only the states before and after it can be observed
+    pub fn atomic(&self) -> bool {
+        self.states.len() <= 2
+    }
+}
+
+/// semantic states at exactly a checkpoint
+pub struct CheckpointReport {
+    pub value: u8,
+    pub states: States,
+    /// whether the test expects a single final state
+    pub expect_sfs: bool,
+}
+
+impl CheckpointReport {
+    pub fn sfs(&self) -> bool {
+        self.states.len() <= 1
+    }
+}
+
+pub struct Report {
+    pub pmem_images: Option<usize>,
+    pub nvme_images: Option<usize>,
+    pub crash_images: usize,
+    pub semantic_states: usize,
+    pub intervals: Vec<IntervalReport>,
+    pub checkpoints: Vec<CheckpointReport>,
+    /// crash images for which recovery or the state dump failed
+    pub failed: BTreeSet<CrashImages>,
+}
+
+impl Report {
+    /// Analyse the checkpoints within the checkpoint range (inclusive) of the test.
+    pub fn analyse(indices: &Indices, test_config: &TestConfig) -> Result<Self> {
+        let checkpoint_range = test_config.checkpoint_range;
+        let in_range = |value: u8| checkpoint_range.0 <= value && value <= checkpoint_range.1;
+        if let Some(expect) = &test_config.expect {
+            for (start, end) in expect.atomic.iter().copied() {
+                if end != start.wrapping_add(1) || !in_range(start) || !in_range(end) {
+                    bail!("expected atomic operation [{}..{}] is not between two consecutive checkpoints in {:?}",
+                        start, end, checkpoint_range);
+                }
+            }
+            if let Some(value) = expect.sfs.iter().find(|value| !in_range(**value)) {
+                bail!("expected SFS checkpoint {} is not in {:?}", value, checkpoint_range);
+            }
+        }
+        let expect_atomic = |start: u8| test_config.expect.as_ref()
+            .is_some_and(|expect| expect.atomic.contains(&(start, start + 1)));
+        let expect_sfs = |value: u8| test_config.expect.as_ref()
+            .is_some_and(|expect| expect.sfs.contains(&value));
+
+        let checkpoint_id = |value: u8| -> Result<usize> {
+            indices.checkpoints.get(&value).copied()
+                .with_context(|| format!("checkpoint {} missing in checkpoint.index", value))
+        };
+        let crash_times = indices.crash_times();
+        let states_where = |pred: &dyn Fn(usize) -> bool| -> States {
+            let mut states = States::new();
+            for (state, images, times) in crash_times.iter() {
+                if times.iter().any(|id| pred(*id)) {
+                    states.entry((*state).clone()).or_default().insert((*images).clone());
+                }
+            }
+            states
+        };
+
+        let mut intervals = Vec::new();
+        for start in checkpoint_range.0..checkpoint_range.1 {
+            let (start_id, end_id) = (checkpoint_id(start)?, checkpoint_id(start + 1)?);
+            // crash images at the start checkpoint belong to the previous interval,
+            // except for the very first one
+            let first = start == checkpoint_range.0;
+            let states = states_where(&|id| (id > start_id || (first && id == start_id)) && id <= end_id);
+            intervals.push(IntervalReport { start, end: start + 1, states, expect_atomic: expect_atomic(start) });
+        }
+
+        let mut checkpoints = Vec::new();
+        for value in checkpoint_range.0..=checkpoint_range.1 {
+            let id = checkpoint_id(value)?;
+            checkpoints.push(CheckpointReport { value, states: states_where(&|other| other == id), expect_sfs: expect_sfs(value) });
+        }
+
+        let unique_images = |index: &BTreeMap<usize, BTreeSet<CrashHash>>| {
+            index.values().flatten().collect::<BTreeSet<_>>().len()
+        };
+        Ok(Self {
+            pmem_images: indices.pmem.as_ref().map(unique_images),
+            nvme_images: indices.nvme.as_ref().map(unique_images),
+            crash_images: indices.states.values().map(|images| images.len()).sum(),
+            semantic_states: indices.states.len(),
+            intervals,
+            checkpoints,
+            failed: indices.states.get(&StateHash::of(FAILED_STATE_DUMP)).into_iter().flatten().cloned().collect(),
+        })
+    }
+}
+
+const COL_GREEN: &str = "\x1b[92m";
+const COL_RED: &str = "\x1b[91m";
+const COL_END: &str = "\x1b[0m";
+
+impl Report {
+    /// Print a colored, human-readable report.
+    pub fn print(&self) {
+        if let Some(count) = self.pmem_images {
+            println!("pmem images: {}", count);
+        }
+        if let Some(count) = self.nvme_images {
+            println!("nvme images: {}", count);
+        }
+        println!("tested images: {}", self.crash_images);
+        println!("number of semantic states: {}", self.semantic_states);
+
+        println!();
+        println!("number of semantic states per logical operation:");
+        for interval in &self.intervals {
+            let msg = match (interval.atomic(), interval.expect_atomic) {
+                (true, true) => format!("{}atomic{}", COL_GREEN, COL_END),
+                (false, true) => format!("{}not atomic{}", COL_RED, COL_END),
+                (true, false) => "atomic (not checked)".to_string(),
+                (false, false) => "not atomic (not checked)".to_string(),
+            };
+            println!("[{}..{}]: {} -> {}", interval.start, interval.end, interval.states.len(), msg);
+        }
+
+        println!();
+        println!("single final state:");
+        for checkpoint in &self.checkpoints {
+            let msg = match (checkpoint.sfs(), checkpoint.expect_sfs) {
+                (true, true) => format!("{}SFS{}", COL_GREEN, COL_END),
+                (false, true) => format!("{}not SFS{}", COL_RED, COL_END),
+                (true, false) => "SFS (not checked)".to_string(),
+                (false, false) => "not SFS (not checked)".to_string(),
+            };
+            println!("checkpoint {}: {}", checkpoint.value, msg);
+        }
+
+        println!();
+        if self.passed() {
+            println!("{}all expectations met{}", COL_GREEN, COL_END);
+        } else {
+            println!("{}expectations violated{}", COL_RED, COL_END);
+        }
+    }
+}
+
+fn read_config<T: DeserializeOwned>(work_dir: &String, name: &str) -> Result<T> {
+    let path = format!("{}/{}", work_dir, name);
+    let file = File::open(path.as_str()).with_context(|| format!("could not open {}", path))?;
+    serde_yaml::from_reader(BufReader::new(file)).with_context(|| format!("could not parse {}", path))
+}
+
+/// Analyse the results of a work dir.
+pub fn analyse(work_dir: &String) -> Result<Report> {
+    let vm_config: VmConfig = read_config(work_dir, "vm_config.yaml")?;
+    let test_config: TestConfig = read_config(work_dir, "test_config.yaml")?;
+
+    let indices = Indices::read(work_dir, &vm_config).context("could not read index files")?;
+    Report::analyse(&indices, &test_config).context("could not analyse results")
+}
+
+/// Analyse the results of a work dir, print them and write report.json and report.xml.
+/// Without `name`, the name of the work dir is used as the test name.
+pub fn report(work_dir: &String, name: Option<&str>) -> Result<Report> {
+    let report = analyse(work_dir)?;
+    report.print();
+
+    let name = name.map_or_else(|| summary::test_name(work_dir), str::to_string);
+    summary::write_files(work_dir, name.as_str(), &report)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use permanent_common::config::Expect;
+
+    fn crash_hash(n: u8) -> CrashHash {
+        CrashHash::of(&[n])
+    }
+
+    fn state_hash(n: u8) -> StateHash {
+        StateHash::of(&[n])
+    }
+
+    fn test_config(expect: Option<Expect>) -> TestConfig {
+        TestConfig {
+            trace_cmd_suffix: String::new(),
+            checkpoint_range: (0, 2),
+            dump_cmd_suffix: String::new(),
+            expect,
+            model: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_analyse_pmem() {
+        let (a, b, c) = (crash_hash(0), crash_hash(1), crash_hash(2));
+        let indices = Indices {
+            checkpoints: BTreeMap::from([(0, 10), (1, 20), (2, 30)]),
+            pmem: Some(BTreeMap::from([
+                (10, BTreeSet::from([a.clone()])),
+                (15, BTreeSet::from([a.clone(), b.clone()])),
+                (20, BTreeSet::from([b.clone()])),
+                (25, BTreeSet::from([b.clone(), c.clone()])),
+                (30, BTreeSet::from([b.clone(), c.clone()])),
+            ])),
+            nvme: None,
+            states: BTreeMap::from([
+                (state_hash(0), vec![CrashImages { pmem: Some(a), nvme: None }]),
+                (state_hash(1), vec![CrashImages { pmem: Some(b), nvme: None }]),
+                (state_hash(2), vec![CrashImages { pmem: Some(c), nvme: None }]),
+            ]),
+        };
+        let report = Report::analyse(&indices, &test_config(None)).unwrap();
+        assert_eq!(report.pmem_images, Some(3));
+        assert_eq!(report.crash_images, 3);
+        assert_eq!(report.intervals.iter().map(|i| i.states.len()).collect::<Vec<_>>(), vec![2, 2]);
+        assert!(report.intervals.iter().all(|i| i.atomic()));
+        assert_eq!(report.checkpoints.iter().map(|c| c.sfs()).collect::<Vec<_>>(), vec![true, true, false]);
+        assert!(report.failed.is_empty());
+        // nothing is checked without expectations
+        assert!(report.passed());
+        assert!(report.test_cases().is_empty());
+
+        let expect = Expect { atomic: vec![(1, 2)], sfs: vec![0, 1] };
+        let report = Report::analyse(&indices, &test_config(Some(expect))).unwrap();
+        assert!(report.passed());
+        assert_eq!(report.test_cases().len(), 3);
+
+        let expect = Expect { atomic: vec![(0, 2)], sfs: vec![] };
+        assert!(Report::analyse(&indices, &test_config(Some(expect))).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+use clap::Parser;
+
+fn main() {
+    let args = Args::parse();
+    let report = permanent_report::report(&args.work_dir, args.name.as_deref()).expect("could not create report");
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    work_dir: String,
+    /// test name used in the JSON summary and JUnit XML (default: name of the work dir)
+    #[clap(short, long)]
+    name: Option<String>,
+}
@@ -0,0 +1,200 @@
+//! Machine-readable output of reports: a JSON summary and JUnit XML.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{CrashImages, Report, States};
+
+/// a single checked property of a report
+pub struct TestCase<'a> {
+    pub name: String,
+    /// states that violate the property, if any
+    pub failure: Option<(String, &'a States)>,
+}
+
+impl Report {
+    /// The expected properties of the test. Unchecked intervals and checkpoints are left out.
+    pub fn test_cases(&self) -> Vec<TestCase<'_>> {
+        let mut cases = Vec::new();
+        for interval in self.intervals.iter().filter(|interval| interval.expect_atomic) {
+            cases.push(TestCase {
+                name: format!("interval [{}..{}] atomic", interval.start, interval.end),
+                failure: (!interval.atomic())
+                    .then(|| (format!("{} semantic states", interval.states.len()), &interval.states)),
+            });
+        }
+        for checkpoint in self.checkpoints.iter().filter(|checkpoint| checkpoint.expect_sfs) {
+            cases.push(TestCase {
+                name: format!("checkpoint {} SFS", checkpoint.value),
+                failure: (!checkpoint.sfs())
+                    .then(|| (format!("{} final states", checkpoint.states.len()), &checkpoint.states)),
+            });
+        }
+        cases
+    }
+
+    pub fn passed(&self) -> bool {
+        self.test_cases().iter().all(|case| case.failure.is_none())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Summary<'a> {
+    pub test: &'a str,
+    pub passed: bool,
+    pub pmem_images: Option<usize>,
+    pub nvme_images: Option<usize>,
+    pub crash_images: usize,
+    pub semantic_states: usize,
+    pub intervals: Vec<IntervalSummary<'a>>,
+    pub checkpoints: Vec<CheckpointSummary<'a>>,
+    /// crash images for which recovery or the state dump failed
+    pub failed_crash_images: &'a BTreeSet<CrashImages>,
+}
+
+#[derive(Serialize)]
+pub struct IntervalSummary<'a> {
+    pub start: u8,
+    pub end: u8,
+    pub state_count: usize,
+    pub atomic: bool,
+    pub expect_atomic: bool,
+    /// semantic states and their crash images, only for non-atomic intervals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failing_states: Option<&'a States>,
+}
+
+#[derive(Serialize)]
+pub struct CheckpointSummary<'a> {
+    pub value: u8,
+    pub state_count: usize,
+    pub sfs: bool,
+    pub expect_sfs: bool,
+    /// final states and their crash images, only for checkpoints without SFS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failing_states: Option<&'a States>,
+}
+
+impl<'a> Summary<'a> {
+    pub fn new(test: &'a str, report: &'a Report) -> Self {
+        Self {
+            test,
+            passed: report.passed(),
+            pmem_images: report.pmem_images,
+            nvme_images: report.nvme_images,
+            crash_images: report.crash_images,
+            semantic_states: report.semantic_states,
+            intervals: report.intervals.iter()
+                .map(|interval| IntervalSummary {
+                    start: interval.start,
+                    end: interval.end,
+                    state_count: interval.states.len(),
+                    atomic: interval.atomic(),
+                    expect_atomic: interval.expect_atomic,
+                    failing_states: (!interval.atomic()).then_some(&interval.states),
+                })
+                .collect(),
+            checkpoints: report.checkpoints.iter()
+                .map(|checkpoint| CheckpointSummary {
+                    value: checkpoint.value,
+                    state_count: checkpoint.states.len(),
+                    sfs: checkpoint.sfs(),
+                    expect_sfs: checkpoint.expect_sfs,
+                    failing_states: (!checkpoint.sfs()).then_some(&checkpoint.states),
+                })
+                .collect(),
+            failed_crash_images: &report.failed,
+        }
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write JUnit XML with one test suite per test. Every expected property is a test case. A test
+/// without a report, given as an error message, has a single test case with that error.
+pub fn write_junit<W: Write>(dst: &mut W, suites: &[(&str, Result<&Report, &str>)]) -> io::Result<()> {
+    let suite_cases: Vec<(&str, Result<Vec<TestCase>, &str>)> = suites.iter()
+        .map(|(name, report)| (*name, report.map(|report| report.test_cases())))
+        .collect();
+    let count = |cases: &Result<Vec<TestCase>, &str>| cases.as_ref().map_or(1, |cases| cases.len());
+    let failures = |cases: &Result<Vec<TestCase>, &str>| cases.as_ref()
+        .map_or(0, |cases| cases.iter().filter(|case| case.failure.is_some()).count());
+    let errors = |cases: &Result<Vec<TestCase>, &str>| cases.is_err() as usize;
+
+    writeln!(dst, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(dst, r#"<testsuites tests="{}" failures="{}" errors="{}">"#,
+        suite_cases.iter().map(|(_, cases)| count(cases)).sum::<usize>(),
+        suite_cases.iter().map(|(_, cases)| failures(cases)).sum::<usize>(),
+        suite_cases.iter().map(|(_, cases)| errors(cases)).sum::<usize>())?;
+    for (name, cases) in &suite_cases {
+        let name = xml_escape(name);
+        writeln!(dst, r#"  <testsuite name="{}" tests="{}" failures="{}" errors="{}">"#,
+            name, count(cases), failures(cases), errors(cases))?;
+        let cases = match cases {
+            Ok(cases) => cases,
+            Err(msg) => {
+                writeln!(dst, r#"    <testcase classname="{}" name="run">"#, name)?;
+                writeln!(dst, r#"      <error message="{}"/>"#, xml_escape(msg))?;
+                writeln!(dst, "    </testcase>")?;
+                writeln!(dst, "  </testsuite>")?;
+                continue;
+            },
+        };
+        for case in cases {
+            let case_name = xml_escape(case.name.as_str());
+            match &case.failure {
+                None => writeln!(dst, r#"    <testcase classname="{}" name="{}"/>"#, name, case_name)?,
+                Some((message, states)) => {
+                    let mut details = String::new();
+                    for (state, images) in states.iter() {
+                        let _ = writeln!(details, "state {}:", state);
+                        for image in images {
+                            let _ = writeln!(details, "  {}", image);
+                        }
+                    }
+                    writeln!(dst, r#"    <testcase classname="{}" name="{}">"#, name, case_name)?;
+                    writeln!(dst, r#"      <failure message="{}">{}</failure>"#, xml_escape(message), xml_escape(details.as_str()))?;
+                    writeln!(dst, "    </testcase>")?;
+                },
+            }
+        }
+        writeln!(dst, "  </testsuite>")?;
+    }
+    writeln!(dst, "</testsuites>")?;
+    Ok(())
+}
+
+/// default test name: the name of the work dir
+pub fn test_name(work_dir: &str) -> String {
+    Path::new(work_dir).file_name().map_or(work_dir.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Write report.json and report.xml into the work dir.
+pub fn write_files(work_dir: &String, name: &str, report: &Report) -> Result<()> {
+    let file = File::create(format!("{}/report.json", work_dir).as_str()).context("could not create report.json")?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &Summary::new(name, report)).context("could not write report.json")?;
+    let file = File::create(format!("{}/report.xml", work_dir).as_str()).context("could not create report.xml")?;
+    let mut writer = BufWriter::new(file);
+    write_junit(&mut writer, &[(name, Ok(report))]).context("could not write report.xml")?;
+    writer.flush().context("could not write report.xml")?;
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use serde::{Serialize, Deserialize};
+use itertools::Itertools;
+use permanent_common::config::{capture_hashes, VmConfig, TraceConfig, TraceType};
+use permanent_common::hash::{ConfigHash, CrashHash, StateHash, FAILED_STATE_DUMP};
+
+const START_MSG: &'static str = "PERMANENT START";
+const END_MSG: &'static str = "PERMANENT END";
+const SUCCESS_MSG: &'static str = "PERMANENT SUCCESS";
+
+/// entry of the append-only results log, written as soon as a job has finished
+#[derive(Serialize, Deserialize)]
+struct JobResult {
+    crash_hash: String,
+    state_hash: StateHash,
+}
+
+/// first line of the results log. The results are only reused if the configs did not change.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct LogHeader {
+    vm_config_hash: Option<ConfigHash>,
+    test_config_hash: Option<ConfigHash>,
+}
+
+impl LogHeader {
+    fn of(work_dir: &str) -> Self {
+        let (vm_config_hash, test_config_hash) = capture_hashes(work_dir).unwrap_or_default();
+        Self { vm_config_hash, test_config_hash }
+    }
+}
+
+/// a single post failure run
+#[derive(Default)]
+pub struct Job {
+    pub pmem_hash: Option<CrashHash>,
+    pub nvme_hash: Option<CrashHash>,
+    /// full images to boot instead of the crash images with the hashes, which then only name the
+    /// trace dir
+    pub pmem_image: Option<String>,
+    pub nvme_image: Option<String>,
+    /// dir of the trace dir instead of the work dir
+    pub trace_parent: Option<String>,
+}
+
+impl Job {
+    /// name of the crash images as used in states.index
+    fn name(&self) -> String {
+        [&self.pmem_hash, &self.nvme_hash].into_iter().flatten().join("_")
+    }
+
+    fn trace_config(&self, work_dir: &String) -> TraceConfig {
+        let mut config = TraceConfig::new(work_dir, TraceType::PostFailure {
+            pmem_hash: self.pmem_hash.as_ref().map(|hash| hash.to_string()),
+            nvme_hash: self.nvme_hash.as_ref().map(|hash| hash.to_string()),
+        });
+        if let Some(parent) = &self.trace_parent {
+            config.set_parent_dir(parent.as_str());
+        }
+        config
+    }
+}
+
+/// Run the post failure traces of all crash images and write states.index. Up to `parallel` VMs run
+/// in parallel. Traces recorded in the results log of a previous run are skipped.
+pub fn run_tests(work_dir: &String, vm_config: &VmConfig, snapshot: bool, parallel: usize) {
+    std::fs::create_dir_all(format!("{}/states", work_dir).as_str()).expect("could not create states dir");
+
+    let (p, n) = vm_config.have_pmem_nvme();
+    let jobs = if p && n {
+        collect_hybrid_jobs(work_dir)
+    } else if p || n {
+        collect_single_jobs(work_dir, p)
+    } else {
+        unreachable!();
+    };
+
+    // results of previous runs
+    let log_path = format!("{}/states.log", work_dir);
+    let header = LogHeader::of(work_dir);
+    let previous = read_results_log(&log_path, &header);
+    let resume = previous.is_some();
+    let mut state_of: HashMap<String, StateHash> = previous.unwrap_or_default();
+    let pending: Vec<usize> = (0..jobs.len()).filter(|i| !state_of.contains_key(&jobs[*i].name())).collect();
+    if pending.len() < jobs.len() {
+        println!("{} of {} traces already done. resume.", jobs.len() - pending.len(), jobs.len());
+    }
+    let mut log = if resume {
+        let mut log = OpenOptions::new().read(true).append(true).open(log_path.as_str())
+            .expect("could not open results log");
+        // a previous run might have died while writing the last entry
+        if log.metadata().expect("could not open results log").len() > 0 {
+            let mut last = [0u8; 1];
+            log.seek(SeekFrom::End(-1)).and_then(|_| log.read_exact(&mut last)).expect("could not read results log");
+            if last[0] != b'\n' {
+                log.write_all(b"\n").expect("could not write results log");
+            }
+        }
+        log
+    } else {
+        let mut log = File::create(log_path.as_str()).expect("could not create results log");
+        serde_json::to_writer(&mut log, &header).expect("could not write results log");
+        log.write_all(b"\n").expect("could not write results log");
+        log
+    };
+
+    let snapshot = snapshot && !pending.is_empty() && take_snapshot(work_dir);
+
+    let next_job = AtomicUsize::new(0);
+    let (result_send, result_recv) = mpsc::channel::<(usize, Option<Vec<u8>>)>();
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.max(1) {
+            let result_send = result_send.clone();
+            let (jobs, pending, next_job, work_dir) = (&jobs, &pending, &next_job, work_dir);
+            scope.spawn(move || {
+                loop {
+                    let i = next_job.fetch_add(1, Ordering::SeqCst);
+                    if i >= pending.len() {
+                        break;
+                    }
+                    let result = run_job(work_dir, &jobs[pending[i]], snapshot, parallel > 1);
+                    result_send.send((pending[i], result)).expect("could not send result");
+                }
+            });
+        }
+        drop(result_send);
+
+        for (c, (i, result)) in result_recv.iter().enumerate() {
+            println!("[{}/{}] trace {} done", c + 1, pending.len(), jobs[i].name());
+            let Some(state_dump) = result else { continue };
+            let state_hash = StateHash::of(state_dump.as_slice());
+            // write the state before the log entry, so that every logged state exists
+            let state_path = format!("{}/states/{}.state", work_dir, state_hash);
+            if !Path::new(state_path.as_str()).exists() {
+                let mut f = File::create(state_path.as_str()).expect("could not create state file");
+                f.write_all(state_dump.as_slice()).expect("could not write state file");
+                f.sync_all().expect("could not write state file");
+            }
+            let entry = JobResult { crash_hash: jobs[i].name(), state_hash };
+            serde_json::to_writer(&mut log, &entry).expect("could not write results log");
+            log.write_all(b"\n").expect("could not write results log");
+            log.sync_data().expect("could not write results log");
+            state_of.insert(entry.crash_hash, entry.state_hash);
+        }
+    });
+
+    // aggregate in job order so that the index does not depend on completion order
+    let mut state_hashes: BTreeMap<StateHash, Vec<String>> = BTreeMap::new();
+    for job in jobs.iter() {
+        let name = job.name();
+        if let Some(state_hash) = state_of.get(&name) {
+            state_hashes.entry(state_hash.clone()).or_insert(Vec::new()).push(name);
+        }
+    }
+    let out_file = File::create(format!("{}/states.index", work_dir).as_str()).expect("could not create output file");
+    serde_json::to_writer_pretty(BufWriter::new(out_file), &state_hashes).expect("could not write output");
+}
+
+/// Read the results log of previous runs. Maps crash hashes to state hashes. None if there is no
+/// log or it was written with different configs.
+fn read_results_log(path: &String, header: &LogHeader) -> Option<HashMap<String, StateHash>> {
+    if !Path::new(path.as_str()).exists() {
+        return None;
+    }
+    let file = File::open(path.as_str()).expect("could not open results log");
+    let mut lines = BufReader::new(file).lines();
+    let first = lines.next().transpose().expect("could not read results log");
+    if first.and_then(|line| serde_json::from_str::<LogHeader>(line.as_str()).ok()).as_ref() != Some(header) {
+        println!("the configs changed since the results log was written. discard it.");
+        return None;
+    }
+    let mut results = HashMap::new();
+    for line in lines {
+        let line = line.expect("could not read results log");
+        match serde_json::from_str::<JobResult>(line.as_str()) {
+            Ok(entry) => { results.insert(entry.crash_hash, entry.state_hash); },
+            // a previous run might have died while writing the last entry
+            Err(_) => eprintln!("WARNING: ignoring invalid results log entry: {}", line),
+        }
+    }
+    Some(results)
+}
+
+/// All combinations of pmem and nvme crash images that were generated at the same trace entry.
+fn collect_hybrid_jobs(work_dir: &String) -> Vec<Job> {
+    let pmem_index: HashMap<usize, HashSet<CrashHash>> = serde_json::from_reader(
+        BufReader::new(File::open(format!("{}/pmem.index", work_dir).as_str()).unwrap())
+    ).unwrap();
+    let nvme_index: HashMap<usize, HashSet<CrashHash>> = serde_json::from_reader(
+        BufReader::new(File::open(format!("{}/nvme.index", work_dir).as_str()).unwrap())
+    ).unwrap();
+    let mut gen_indices_pmem: Vec<usize> = pmem_index.keys().copied().collect();
+    gen_indices_pmem.sort();
+    let mut gen_indices_nvme: Vec<usize> = nvme_index.keys().copied().collect();
+    gen_indices_nvme.sort();
+    if gen_indices_pmem != gen_indices_nvme {
+        panic!("index file key discrepancy");
+    }
+    let gen_indices = gen_indices_pmem;
+
+    let mut jobs = Vec::new();
+    let mut seen: HashSet<(CrashHash, CrashHash)> = HashSet::new();
+    for id in gen_indices {
+        let pmem_hashes = pmem_index.get(&id).unwrap().iter().sorted();
+        let nvme_hashes = nvme_index.get(&id).unwrap().iter().sorted();
+        for (pmem_hash, nvme_hash) in pmem_hashes.cartesian_product(nvme_hashes) {
+            let combination = (pmem_hash.clone(), nvme_hash.clone());
+            if seen.insert(combination) {
+                jobs.push(Job { pmem_hash: Some(pmem_hash.clone()), nvme_hash: Some(nvme_hash.clone()), ..Default::default() });
+            }
+        }
+    }
+    jobs
+}
+
+/// All crash images of a pmem-only or nvme-only run.
+fn collect_single_jobs(work_dir: &String, pmem: bool) -> Vec<Job> {
+    let mut crash_hashes: Vec<CrashHash> = std::fs::read_dir(format!("{}/crash_images", work_dir).as_str())
+        .expect("could not read crash_image dir")
+        .map(|path| {
+            let filename = path.unwrap().file_name();
+            let pathref: &Path = filename.as_ref();
+            pathref.file_stem().unwrap().to_str().unwrap().parse().expect("invalid crash image name")
+        })
+        .collect();
+    // an image might be stored both as a delta and in full
+    crash_hashes.sort();
+    crash_hashes.dedup();
+    crash_hashes.into_iter()
+        .map(|crash_hash| if pmem {
+            Job { pmem_hash: Some(crash_hash), ..Default::default() }
+        } else {
+            Job { nvme_hash: Some(crash_hash), ..Default::default() }
+        })
+        .collect()
+}
+
+/// Run the post failure trace of a single combination of crash images. They are read from the
+/// crash_images directory of the work dir, unless the job gives a full image. Returns the state
+/// dump, or None if the trace could not be run.
+pub fn run_job(work_dir: &String, job: &Job, snapshot: bool, quiet: bool) -> Option<Vec<u8>> {
+    let dir = job.trace_config(work_dir).trace_dir();
+    let mut command = permanent_trace_command();
+    // an earlier, interrupted run might have left the trace dir behind
+    command.arg("post-failure").arg(work_dir.as_str()).arg("--force");
+    if let Some(hash) = &job.pmem_hash {
+        command.args(["--pmem-hash", hash.to_string().as_str()]);
+    }
+    if let Some(hash) = &job.nvme_hash {
+        command.args(["--nvme-hash", hash.to_string().as_str()]);
+    }
+    if let Some(path) = &job.pmem_image {
+        command.args(["--pmem-image", path.as_str()]);
+    }
+    if let Some(path) = &job.nvme_image {
+        command.args(["--nvme-image", path.as_str()]);
+    }
+    if let Some(parent) = &job.trace_parent {
+        command.args(["--trace-parent", parent.as_str()]);
+    }
+    command.args(snapshot.then_some("--snapshot"));
+    if quiet {
+        // the outputs of parallel runs would interleave. the VM logs are kept in the trace dir.
+        command.stdout(Stdio::null());
+    }
+    let success = command
+        .spawn()
+        .expect("could not start permanent_trace")
+        .wait()
+        .expect("could not collect permanent_trace process")
+        .success();
+    let result = if !success {
+        eprintln!("WARNING: trace {} returned non-zero exit status. skipped.", job.name());
+        None
+    } else {
+        let log = std::fs::read(format!("{}/log", dir).as_str()).expect("could not read log");
+        let success = log.windows(SUCCESS_MSG.len()).any(|win| win == SUCCESS_MSG.as_bytes());
+        let state_dump = if success {
+            extract_state_dump(log.as_slice())
+        } else {
+            FAILED_STATE_DUMP
+        };
+        Some(state_dump.to_vec())
+    };
+    clean_dir(&dir);
+    result
+}
+
+/// permanent_trace is expected next to the running binary, which is true for all binaries of
+/// the workspace
+fn permanent_trace_command() -> Command {
+    let exe = std::env::current_exe().expect("could not locate the running binary");
+    Command::new(exe.with_file_name("permanent_trace"))
+}
+
+/// Boot the VM once and save its state, so that post failure runs can skip booting.
+pub fn take_snapshot(work_dir: &String) -> bool {
+    println!("take snapshot");
+    let success = permanent_trace_command()
+        .arg("snapshot")
+        .arg(work_dir.as_str())
+        .arg("--force")
+        .spawn()
+        .expect("could not start permanent_trace")
+        .wait()
+        .expect("could not collect permanent_trace process")
+        .success();
+    if !success {
+        eprintln!("WARNING: could not take snapshot. falling back to cold boot.");
+    }
+    success
+}
+
+fn extract_state_dump(data: &[u8]) -> &[u8] {
+    let start_pos = data.windows(START_MSG.len()).position(|win| win == START_MSG.as_bytes()).expect("no START")
+        + START_MSG.len();
+    let end_pos = data.windows(END_MSG.len()).position(|win| win == END_MSG.as_bytes()).expect("no END");
+    &data[start_pos..end_pos]
+}
+
+// remove everything except logs for debugging
+fn clean_dir(dir: &String) {
+    let files = ["trace.bin", "pmem.raw", "nvme.raw", "pipe.in", "pipe.out"];
+    for file in files {
+        let file = format!("{}/{}", dir, file);
+        if Path::new(file.as_str()).exists() {
+            if let Err(_) = std::fs::remove_file(file.as_str()) {
+                eprintln!("WARNING: could not remove {}", file);
+            }
+        }
+    }
+}
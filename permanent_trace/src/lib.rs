@@ -0,0 +1,35 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use permanent_common::config::{VmConfig, TestConfig, TraceConfig};
+
+mod pipe;
+mod monitor;
+mod vm;
+pub mod tracer;
+
+pub fn remove_dir(path: &String) -> Result<(), std::io::Error> {
+    if Path::new(path).exists() {
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+pub fn read_vm_config(work_dir: &String) -> VmConfig {
+    let vm_config_file = File::open(format!("{}/vm_config.yaml", work_dir).as_str()).expect("Could not open vm config file");
+    serde_yaml::from_reader(BufReader::new(vm_config_file)).expect("Could not deserialize vm config file")
+}
+
+pub fn read_test_config(work_dir: &String) -> TestConfig {
+    let test_config_file = File::open(format!("{}/test_config.yaml", work_dir).as_str()).expect("Could not open test config file");
+    serde_yaml::from_reader(BufReader::new(test_config_file)).expect("Could not deserialize test config file")
+}
+
+/// Create the trace dir and run the VM. With `force`, an existing trace dir is removed first.
+pub fn trace(work_dir: &String, vm_config: &VmConfig, test_config: Option<&TestConfig>, trace_config: &TraceConfig, force: bool, snapshot: bool) {
+    if force {
+        remove_dir(&trace_config.trace_dir()).unwrap();
+    }
+    std::fs::create_dir(trace_config.trace_dir()).expect("could not create trace dir");
+    tracer::trace_vm(work_dir, vm_config, test_config, trace_config, snapshot);
+}
@@ -1,29 +1,6 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
 use clap::{Parser, Subcommand};
-use permanent_common::config::{VmConfig, TestConfig, TraceConfig, TraceType};
-
-mod pipe;
-mod vm;
-mod tracer;
-
-fn remove_dir(path: &String) -> Result<(), std::io::Error> {
-    if Path::new(path).exists() {
-        std::fs::remove_dir_all(path)?;
-    }
-    Ok(())
-}
-
-fn read_vm_config(work_dir: &String) -> VmConfig {
-    let vm_config_file = File::open(format!("{}/vm_config.yaml", work_dir).as_str()).expect("Could not open vm config file");
-    serde_yaml::from_reader(BufReader::new(vm_config_file)).expect("Could not deserialize vm config file")
-}
-
-fn read_test_config(work_dir: &String) -> TestConfig {
-    let test_config_file = File::open(format!("{}/test_config.yaml", work_dir).as_str()).expect("Could not open test config file");
-    serde_yaml::from_reader(BufReader::new(test_config_file)).expect("Could not deserialize test config file")
-}
+use permanent_common::config::{TraceConfig, TraceType};
+use permanent_trace::{read_test_config, read_vm_config, trace};
 
 fn main() {
     let args = Args::parse();
@@ -34,25 +11,31 @@ fn main() {
             let test_config = read_test_config(&work_dir);
             let trace_config = TraceConfig::new(&work_dir, TraceType::Analyse);
 
-            if force {
-                remove_dir(&trace_config.trace_dir()).unwrap();
-            }
-            std::fs::create_dir(trace_config.trace_dir()).expect("could not create trace dir");
-            tracer::trace_vm(&work_dir, &vm_config, Some(&test_config), &trace_config);
+            trace(&work_dir, &vm_config, Some(&test_config), &trace_config, force, false);
         },
         Command::PostSuccess { work_dir, pmem_hash, nvme_hash, force } => {
-            todo!();
-        },
-        Command::PostFailure { work_dir, pmem_hash, nvme_hash, force } => {
-                let vm_config = read_vm_config(&work_dir);
-                let test_config = read_test_config(&work_dir);
-            let trace_config = TraceConfig::new(&work_dir, TraceType::PostFailure { pmem_hash, nvme_hash });
+            let vm_config = read_vm_config(&work_dir);
+            let trace_config = TraceConfig::new(&work_dir, TraceType::PostSuccess { pmem_hash, nvme_hash });
 
-            if force {
-                remove_dir(&trace_config.trace_dir()).unwrap();
+            trace(&work_dir, &vm_config, None, &trace_config, force, false);
+        },
+        Command::PostFailure { work_dir, pmem_hash, nvme_hash, pmem_image, nvme_image, trace_parent, force, snapshot } => {
+            let vm_config = read_vm_config(&work_dir);
+            let test_config = read_test_config(&work_dir);
+            let mut trace_config = TraceConfig::new(&work_dir, TraceType::PostFailure { pmem_hash, nvme_hash });
+            trace_config.pmem_image_source = pmem_image;
+            trace_config.nvme_image_source = nvme_image;
+            if let Some(parent) = trace_parent {
+                trace_config.set_parent_dir(parent.as_str());
             }
-            std::fs::create_dir(trace_config.trace_dir()).expect("could not create trace dir");
-            tracer::trace_vm(&work_dir, &vm_config, Some(&test_config), &trace_config);
+
+            trace(&work_dir, &vm_config, Some(&test_config), &trace_config, force, snapshot);
+        },
+        Command::Snapshot { work_dir, force } => {
+            let vm_config = read_vm_config(&work_dir);
+            let trace_config = TraceConfig::new(&work_dir, TraceType::Snapshot);
+
+            trace(&work_dir, &vm_config, None, &trace_config, force, false);
         }
     }
 }
@@ -85,7 +68,25 @@ enum Command {
         pmem_hash: Option<String>,
         #[arg(short, long)]
         nvme_hash: Option<String>,
+        /// boot this full pmem image instead of the crash image of --pmem-hash
+        #[arg(long, requires = "pmem_hash")]
+        pmem_image: Option<String>,
+        /// boot this full nvme image instead of the crash image of --nvme-hash
+        #[arg(long, requires = "nvme_hash")]
+        nvme_image: Option<String>,
+        /// create the trace dir in this dir instead of the work dir
+        #[arg(long)]
+        trace_parent: Option<String>,
         #[clap(short, long, action)]
         force: bool,
-    }
+        /// resume from the snapshot instead of booting, if there is one
+        #[clap(short, long, action)]
+        snapshot: bool,
+    },
+    /// boot the VM once and save its state at the shell prompt
+    Snapshot {
+        work_dir: String,
+        #[clap(short, long, action)]
+        force: bool,
+    },
 }
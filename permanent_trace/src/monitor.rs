@@ -0,0 +1,42 @@
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const PROMPT: &[u8] = b"(qemu) ";
+
+/// Connection to the human monitor interface of QEMU.
+pub struct Monitor {
+    stream: UnixStream,
+}
+
+impl Monitor {
+    pub fn connect(path: &String) -> io::Result<Monitor> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+        let mut monitor = Monitor { stream };
+        monitor.read_until_prompt()?;
+        Ok(monitor)
+    }
+
+    /// Read output until the next prompt or until the monitor is closed.
+    fn read_until_prompt(&mut self) -> io::Result<String> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !buf.ends_with(PROMPT) {
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(String::from_utf8_lossy(buf.as_slice()).into_owned())
+    }
+
+    /// Execute a monitor command and return its output.
+    pub fn command(&mut self, cmd: &str) -> io::Result<String> {
+        self.stream.write_fmt(format_args!("{}\n", cmd))?;
+        self.stream.flush()?;
+        self.read_until_prompt()
+    }
+}
@@ -1,18 +1,82 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
 use std::time::SystemTime;
 
 use permanent_common::config::{VmConfig, TestConfig, TraceConfig, TraceType};
+use permanent_common::image::materialize_crash_image;
 use permanent_common::profiler::Measurement;
+use permanent_common::trace::{TraceEntry, PmemEvent, NvmeEvent, parse_trace_file_bin};
 
 use crate::vm::VM;
 use crate::pipe::Pipe;
 
-pub fn trace_vm(work_dir: &String, vm_config: &VmConfig, test_config: Option<&TestConfig>, trace_config: &TraceConfig) {
+/// Run a VM for the given trace type. With `snapshot`, the VM is resumed from the snapshot
+/// in the work dir if there is one, otherwise it is booted normally.
+pub fn trace_vm(work_dir: &String, vm_config: &VmConfig, test_config: Option<&TestConfig>, trace_config: &TraceConfig, snapshot: bool) {
+    // 1. prepare images
+    prepare_images(work_dir, vm_config, trace_config);
+
+    // 2. create pipe
+    Pipe::make(&trace_config.pipe_path()).expect("Could not create control pipe");
+
+    // 3. init vm & wait for startup
+    let snapshot_path = TraceConfig::new(work_dir, TraceType::Snapshot).snapshot_path();
+    let mut vm = if snapshot && Path::new(snapshot_path.as_str()).exists() {
+        VM::resume(vm_config, trace_config, &snapshot_path).unwrap_or_else(|e| {
+            eprintln!("WARNING: could not resume from snapshot: {}. falling back to cold boot.", e);
+            prepare_images(work_dir, vm_config, trace_config);
+            Pipe::make(&trace_config.pipe_path()).expect("Could not create control pipe");
+            VM::init(vm_config, trace_config)
+        })
+    } else {
+        if snapshot {
+            println!("no snapshot found. falling back to cold boot.");
+        }
+        VM::init(vm_config, trace_config)
+    };
+
+    if let TraceType::Snapshot = &trace_config.trace_type {
+        vm.save_snapshot(trace_config).expect("could not save snapshot");
+        return;
+    }
+
+    // 4. run tests & wait for end
+    let text = match &trace_config.trace_type {
+        TraceType::Analyse => {
+            format!("(checkpoint 255 && {} && {} && checkpoint success) || checkpoint fail\n", &vm_config.trace_cmd_prefix, test_config.unwrap().trace_cmd_suffix)
+        },
+        TraceType::PostSuccess { .. } => {
+            format!("(checkpoint 255 && {} && checkpoint success) || checkpoint fail\n", &vm_config.recovery_cmd)
+        },
+        TraceType::PostFailure { .. } => {
+            format!("(checkpoint 255 && {} && {} && checkpoint success) || checkpoint fail\n", &vm_config.dump_cmd_prefix, &test_config.unwrap().dump_cmd_suffix)
+        },
+        TraceType::Snapshot => unreachable!(),
+    };
+    println!("== sh command: {}", text);
+    vm.send(text.as_str()).unwrap();
+
+    // 5. shutdown vm
+    let success = vm.teardown();
+    match &trace_config.trace_type {
+        TraceType::Analyse if !success => {
+            panic!("trace analyse was not successful! try tracing with a different shell command");
+        },
+        TraceType::PostSuccess { .. } if !success => {
+            eprintln!("WARNING: recovery was not successful. the read trace might be incomplete.");
+        },
+        _ => { },
+    }
+}
+
+fn prepare_images(work_dir: &String, vm_config: &VmConfig, trace_config: &TraceConfig) {
     // TODO we always copy, even on PostSuccess.
     // might be a little inefficient, but we can't risk the post recovery to write to NVME.
     // it also writes on mount -oro, in case of recovery.
     let (p, n) = vm_config.have_pmem_nvme();
     match &trace_config.trace_type {
-        TraceType::Analyse => {
+        TraceType::Analyse | TraceType::Snapshot => {
             if p {
                 std::fs::copy(format!("{}/pmem_base.raw", work_dir).as_str(),
                               trace_config.pmem_image_path().as_str())
@@ -24,47 +88,85 @@ pub fn trace_vm(work_dir: &String, vm_config: &VmConfig, test_config: Option<&Te
                     .expect("could not copy base image");
             }
         },
-        TraceType::PostSuccess => { todo!(); },
+        TraceType::PostSuccess { pmem_hash, nvme_hash } => {
+            if p {
+                if let Some(hash) = pmem_hash {
+                    materialize_crash_image(work_dir, hash, trace_config.pmem_image_path().as_str())
+                        .expect("could not materialize crash image");
+                }
+            }
+            if n {
+                if let Some(hash) = nvme_hash {
+                    materialize_crash_image(work_dir, hash, trace_config.nvme_image_path().as_str())
+                        .expect("could not materialize crash image");
+                }
+            }
+            create_final_images(work_dir, trace_config, p && pmem_hash.is_none(), n && nvme_hash.is_none());
+        },
         TraceType::PostFailure { pmem_hash, nvme_hash } => {
             if p {
-                std::fs::copy(format!("{}/crash_images/{}.raw", work_dir, pmem_hash.as_ref().unwrap()).as_str(),
-                              trace_config.pmem_image_path().as_str())
-                    .expect("could not copy base image");
+                prepare_crash_image(work_dir, pmem_hash.as_ref().unwrap(), &trace_config.pmem_image_source, trace_config.pmem_image_path());
             }
             if n {
-                std::fs::copy(format!("{}/crash_images/{}.raw", work_dir, nvme_hash.as_ref().unwrap()).as_str(),
-                              trace_config.nvme_image_path().as_str())
-                    .expect("could not copy base image");
+                prepare_crash_image(work_dir, nvme_hash.as_ref().unwrap(), &trace_config.nvme_image_source, trace_config.nvme_image_path());
             }
         }
     }
+}
 
-    // 2. create pipe
-    Pipe::make(&trace_config.pipe_path()).expect("Could not create control pipe");
-
-    // 3. init vm & wait for startup
-    let mut vm = VM::init(&vm_config, &trace_config);
-
-    // 4. run tests & wait for end
-    let text = match &trace_config.trace_type {
-        TraceType::Analyse => {
-            format!("(checkpoint 255 && {} && {} && checkpoint success) || checkpoint fail\n", &vm_config.trace_cmd_prefix, test_config.unwrap().trace_cmd_suffix)
-        },
-        TraceType::PostSuccess => {
-            format!("(checkpoint 255 && {} && checkpoint success) || checkpoint fail\n", &vm_config.recovery_cmd)
+/// Copy the given full image, or materialize the crash image with the given hash.
+fn prepare_crash_image(work_dir: &String, hash: &str, source: &Option<String>, dst: String) {
+    match source {
+        Some(source) => {
+            std::fs::copy(source.as_str(), dst.as_str()).expect("could not copy crash image");
         },
-        TraceType::PostFailure { .. } => {
-            format!("(checkpoint 255 && {} && {} && checkpoint success) || checkpoint fail\n", &vm_config.dump_cmd_prefix, &test_config.unwrap().dump_cmd_suffix)
-        },
-    };
-    println!("== sh command: {}", text);
-    vm.send(text.as_str()).unwrap();
+        None => materialize_crash_image(work_dir, hash, dst.as_str()).expect("could not materialize crash image"),
+    }
+}
 
-    // 5. shutdown vm
-    let success = vm.teardown();
-    if let TraceType::Analyse = &trace_config.trace_type {
-        if !success {
-            panic!("trace analyse was not successful! try tracing with a different shell command");
+/// Create the final images (everything persisted) by applying all writes of the analyse trace to
+/// the base images.
+fn create_final_images(work_dir: &String, trace_config: &TraceConfig, pmem: bool, nvme: bool) {
+    if !pmem && !nvme {
+        return;
+    }
+    let mut pmem_image = pmem.then(|| std::fs::read(format!("{}/pmem_base.raw", work_dir).as_str())
+        .expect("could not read base image"));
+    let mut nvme_image = nvme.then(|| std::fs::read(format!("{}/nvme_base.raw", work_dir).as_str())
+        .expect("could not read base image"));
+
+    let analyse_config = TraceConfig::new(work_dir, TraceType::Analyse);
+    let trace_file = File::open(analyse_config.trace_path().as_str()).expect("could not open analyse trace file");
+    // the plugin initializes pmem at checkpoint 255, so earlier pmem writes are overwritten anyway
+    let mut trace = parse_trace_file_bin(BufReader::new(trace_file)).expect("invalid analyse trace file");
+    trace.header.check_work_dir(work_dir).expect("analyse trace does not belong to the work dir");
+    let mut had_init = false;
+    for entry in trace.by_ref() {
+        match entry.expect("could not parse analyse trace") {
+            TraceEntry::Pmem { event: PmemEvent::Write { address, size: _, content, non_temporal: _ }, .. } => {
+                if let Some(img) = pmem_image.as_mut().filter(|_| had_init) {
+                    let address = address as usize;
+                    img[address..(address + content.len())].copy_from_slice(content.as_slice());
+                }
+            },
+            TraceEntry::Nvme { id: _, event: NvmeEvent::Write { offset, length: _, data } } => {
+                if let Some(img) = nvme_image.as_mut() {
+                    let offset = offset as usize;
+                    img[offset..(offset + data.len())].copy_from_slice(data.as_slice());
+                }
+            },
+            TraceEntry::Checkpoint { id: _, value: 255 } => { had_init = true; },
+            _ => { },
         }
     }
+    if !trace.is_terminated() {
+        panic!("the analyse trace is truncated. trace again.");
+    }
+
+    if let Some(img) = pmem_image {
+        std::fs::write(trace_config.pmem_image_path().as_str(), img).expect("could not write final image");
+    }
+    if let Some(img) = nvme_image {
+        std::fs::write(trace_config.nvme_image_path().as_str(), img).expect("could not write final image");
+    }
 }
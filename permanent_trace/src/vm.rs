@@ -4,13 +4,27 @@ use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::process::{Child, Command, Stdio};
 use enumset::EnumSet;
 use crate::pipe::Pipe;
+use crate::monitor::Monitor;
 extern crate libc;
 
-use permanent_common::config::{VmConfig, TraceConfig, TraceType, TraceOption, TcgPluginConfig};
+use permanent_common::config::{capture_hashes, VmConfig, TraceConfig, TraceType, TraceOption, TcgPluginConfig};
 
 pub struct VM {
     pipe: Pipe,
-    process: Child,
+    process: Qemu,
+}
+
+/// The qemu process of a VM. It is killed when dropped before it exited, e.g. when the caller
+/// panics and catches the unwind.
+struct Qemu(Child);
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            unsafe { libc::kill(self.0.id() as i32, libc::SIGKILL); }
+            let _ = self.0.wait();
+        }
+    }
 }
 
 impl VM {
@@ -18,9 +32,55 @@ impl VM {
     pub fn init(vm_config: &VmConfig, trace_config: &TraceConfig) -> Self {
         println!("Create VM");
 
-        let io_log_file = File::create(&trace_config.io_log_path()).expect("Could not create io log file");
         let log_file = File::create(&trace_config.log_path()).expect("Could not create log file");
+        let command = Self::qemu_command(vm_config, trace_config);
+
+        println!("== Start QEMU VM");
+        println!("{:?}", command);
+
+        let child = Qemu(Self::spawn(command, trace_config).expect("Could not start qemu vm"));
+
+        // TODO lower?
+        std::thread::sleep(std::time::Duration::from_millis(2000));
+        let mut pipe = Pipe::open(&trace_config.pipe_path(), BufWriter::new(log_file)).expect("Could not open control pipe");
+        println!("Pipes opened");
+
+        pipe.wait_for(b"/bin/sh: can't access tty; job control turned off").unwrap();
+        println!("VM ready");
+
+        return Self { pipe, process: child };
+    }
+
+    /// Start the VM from a snapshot that was taken at the shell prompt instead of booting it.
+    /// The disk contents are not part of the snapshot, so the images of trace_config are used.
+    pub fn resume(vm_config: &VmConfig, trace_config: &TraceConfig, snapshot_path: &String) -> Result<Self, io::Error> {
+        println!("Resume VM");
+
+        let log_file = File::create(trace_config.log_path())?;
+        let mut command = Self::qemu_command(vm_config, trace_config);
+        command.args(["-incoming", format!("exec:cat {}", snapshot_path).as_str()]);
+
+        println!("== Start QEMU VM from snapshot");
+        println!("{:?}", command);
+
+        let mut child = Qemu(Self::spawn(command, trace_config)?);
+
+        std::thread::sleep(std::time::Duration::from_millis(2000));
+        // opening the pipe blocks if there is no qemu on the other side
+        if let Some(status) = child.0.try_wait()? {
+            return Err(io::Error::other(format!("qemu exited early: {}", status)));
+        }
+        let mut pipe = Pipe::open(&trace_config.pipe_path(), BufWriter::new(log_file))?;
+        println!("Pipes opened");
+
+        // the shell prompt has already been printed before the snapshot was taken
+        pipe.send("checkpoint ready\n").and_then(|_| pipe.wait_for(b"PERMANENT READY"))?;
+        println!("VM ready");
 
+        Ok(Self { pipe, process: child })
+    }
+
+    fn qemu_command(vm_config: &VmConfig, trace_config: &TraceConfig) -> Command {
         let mut command = Command::new(&vm_config.qemu_path);
         // add kernel, initrd
         command.args(["-kernel", vm_config.kernel_path.as_str()]);
@@ -29,7 +89,12 @@ impl VM {
         // pipe interface
         command.args(["-serial", format!("pipe:{}", &trace_config.pipe_path()).as_str()]);
         command.arg("-nographic");
-        
+
+        // monitor interface, used to save the vm state
+        if let TraceType::Snapshot = trace_config.trace_type {
+            command.args(["-monitor", format!("unix:{},server=on,wait=off", trace_config.monitor_path()).as_str()]);
+        }
+
         // add nvme drive, if required
         let (_, uses_nvme) = vm_config.have_pmem_nvme();
         if uses_nvme {
@@ -40,6 +105,8 @@ impl VM {
         }
 
         let (p, n) = vm_config.have_pmem_nvme();
+        let (vm_config_hash, test_config_hash) = capture_hashes(trace_config.work_dir().as_str())
+            .expect("could not read the config files");
 
         // add plugin information
         let pmem_trace_what = TraceOption::PmemWrite | TraceOption::PmemFence | TraceOption::PmemFlush;
@@ -55,53 +122,71 @@ impl VM {
                     if n { opts |= nvme_trace_what; }
                     opts
                 },
-                TraceType::PostSuccess => {
+                TraceType::PostSuccess { .. } => {
                     let mut opts = TraceOption::Checkpoint.into();
                     if p { opts |= TraceOption::PmemRead; }
                     if n { opts |= TraceOption::NvmeRead; }
                     opts
                 },
-                TraceType::PostFailure { .. } => EnumSet::empty(),
+                TraceType::PostFailure { .. } | TraceType::Snapshot => EnumSet::empty(),
             },
             out_trace_file: trace_config.trace_path(),
+            // the analyse trace can get large, so make it seekable
+            trace_index: matches!(trace_config.trace_type, TraceType::Analyse),
+            vm_config_hash,
+            test_config_hash,
         };
+        // the plugin is built into the same target dir as the running binary
+        let plugin_path = std::env::current_exe().expect("could not locate the running binary")
+            .with_file_name("libpermanent_plugin.so");
         command.args([
             "-plugin",
-            plugin_config.to_qemu_plugin_arg_string("target/release/libpermanent_plugin.so").as_str()
+            plugin_config.to_qemu_plugin_arg_string(plugin_path.to_str().unwrap()).as_str()
         ]);
 
         // add free-form qemu args
         command.args(vm_config.qemu_args.clone());
+        command
+    }
 
+    fn spawn(mut command: Command, trace_config: &TraceConfig) -> Result<Child, io::Error> {
+        let io_log_file = File::create(trace_config.io_log_path())?;
         command.stderr(unsafe { Stdio::from_raw_fd(io_log_file.into_raw_fd()) });
-
-        println!("== Start QEMU VM");
-        println!("{:?}", command);
-
-        let child = command.spawn().expect("Could not start qemu vm");
-
-        // TODO lower?
-        std::thread::sleep(std::time::Duration::from_millis(2000));
-        let mut pipe = Pipe::open(&trace_config.pipe_path(), BufWriter::new(log_file)).expect("Could not open control pipe");
-        println!("Pipes opened");
-
-        pipe.wait_for(b"/bin/sh: can't access tty; job control turned off").unwrap();
-        println!("VM ready");
-
-        return Self { pipe, process: child };
+        command.spawn()
     }
 
     pub fn teardown(&mut self) -> bool {
         let variants = [b"PERMANENT SUCCESS".as_slice(), b"PERMANENT FAIL".as_slice()];
         let success = self.pipe.wait_for_any(&variants).unwrap() == 0; // make sure we collected all output
         // send SIGTERM for qemu to terminate gracefully
-        unsafe { libc::kill(self.process.id() as i32, libc::SIGTERM); }
+        unsafe { libc::kill(self.process.0.id() as i32, libc::SIGTERM); }
 
-        self.process.wait().expect("Could not collect qemu");
+        self.process.0.wait().expect("Could not collect qemu");
         println!("== Exit QEMU VM");
         return success;
     }
 
+    /// Save the vm state to a file and shut down the VM.
+    pub fn save_snapshot(&mut self, trace_config: &TraceConfig) -> Result<(), io::Error> {
+        let mut monitor = Monitor::connect(&trace_config.monitor_path())?;
+        monitor.command("stop")?;
+        monitor.command(format!("migrate \"exec:cat > {}\"", trace_config.snapshot_path()).as_str())?;
+        loop {
+            let status = monitor.command("info migrate")?;
+            if status.contains("Migration status: completed") {
+                break;
+            } else if status.contains("Migration status: failed") {
+                return Err(io::Error::other("saving the vm state failed"));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }
+        monitor.command("quit")?;
+
+        self.process.0.wait()?;
+        println!("== Exit QEMU VM");
+        Ok(())
+    }
+
     pub fn send(&mut self, text: &str) -> Result<(), io::Error> {
         self.pipe.send(text)
     }